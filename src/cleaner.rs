@@ -3,8 +3,16 @@ use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
 use regex::Regex;
 
-trait Cleaner {
-    fn clean(text: BiText) -> Option<BiText>;
+/// A named cleaner function, so cleaners can be used as pipeline steps.
+pub struct Cleaner {
+    pub name: &'static str,
+    pub clean: fn(Vec<BiText>) -> Vec<BiText>,
+}
+
+impl Cleaner {
+    pub fn new(name: &'static str, clean: fn(Vec<BiText>) -> Vec<BiText>) -> Self {
+        Cleaner { name, clean }
+    }
 }
 
 pub fn whitespace_cleaner(bitext: Vec<BiText>) -> Vec<BiText> {
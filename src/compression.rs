@@ -0,0 +1,173 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use flate2::bufread::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use xz2::bufread::XzDecoder;
+use xz2::write::XzEncoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".gz") {
+            Compression::Gzip
+        } else if path.ends_with(".xz") {
+            Compression::Xz
+        } else if path.ends_with(".zst") || path.ends_with(".zstd") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    pub fn from_magic(bytes: &[u8]) -> Self {
+        if bytes.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if bytes.starts_with(XZ_MAGIC) {
+            Compression::Xz
+        } else if bytes.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Wraps a reader in the decoder matching the magic bytes at its start.
+pub fn decompress<R: BufRead + 'static>(mut reader: R) -> io::Result<Box<dyn BufRead>> {
+    // Pipes can return fewer bytes than the longest magic from one read.
+    let mut magic = Vec::with_capacity(XZ_MAGIC.len());
+    while magic.len() < XZ_MAGIC.len() {
+        let buffer = match reader.fill_buf() {
+            Ok(buffer) => buffer,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        };
+        if buffer.is_empty() {
+            break;
+        }
+        let length = buffer.len().min(XZ_MAGIC.len() - magic.len());
+        magic.extend_from_slice(&buffer[..length]);
+        reader.consume(length);
+    }
+    let compression = Compression::from_magic(&magic);
+    let reader = io::Cursor::new(magic).chain(reader);
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(reader))),
+        Compression::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(reader))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+    })
+}
+
+/// Opens a possibly compressed file for reading, `-` reads from stdin.
+pub fn open(path: &str) -> io::Result<Box<dyn BufRead>> {
+    if path == "-" {
+        return decompress(io::stdin().lock());
+    }
+    decompress(BufReader::new(File::open(path)?))
+}
+
+/// A writer that compresses its output. The compressed stream is only
+/// complete after [`CompressedWriter::finish`] was called.
+pub enum CompressedWriter<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    Xz(XzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> CompressedWriter<W> {
+    pub fn new(writer: W, compression: Compression) -> io::Result<Self> {
+        Ok(match compression {
+            Compression::None => CompressedWriter::Plain(writer),
+            Compression::Gzip => CompressedWriter::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Xz => CompressedWriter::Xz(XzEncoder::new(writer, 6)),
+            Compression::Zstd => CompressedWriter::Zstd(zstd::Encoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+        })
+    }
+
+    pub fn finish(self) -> io::Result<W> {
+        let mut writer = match self {
+            CompressedWriter::Plain(writer) => writer,
+            CompressedWriter::Gzip(writer) => writer.finish()?,
+            CompressedWriter::Xz(writer) => writer.finish()?,
+            CompressedWriter::Zstd(writer) => writer.finish()?,
+        };
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Write for CompressedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressedWriter::Plain(writer) => writer.write(buf),
+            CompressedWriter::Gzip(writer) => writer.write(buf),
+            CompressedWriter::Xz(writer) => writer.write(buf),
+            CompressedWriter::Zstd(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressedWriter::Plain(writer) => writer.flush(),
+            CompressedWriter::Gzip(writer) => writer.flush(),
+            CompressedWriter::Xz(writer) => writer.flush(),
+            CompressedWriter::Zstd(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Creates a file that is compressed according to its extension, `-` writes
+/// uncompressed to stdout.
+pub fn create(path: &str) -> io::Result<CompressedWriter<Box<dyn Write>>> {
+    if path == "-" {
+        return CompressedWriter::new(Box::new(BufWriter::new(io::stdout().lock())), Compression::None);
+    }
+    CompressedWriter::new(Box::new(BufWriter::new(File::create(path)?)), Compression::from_path(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compression_roundtrip() {
+        for compression in [Compression::None, Compression::Gzip, Compression::Xz, Compression::Zstd] {
+            let mut writer = CompressedWriter::new(Vec::new(), compression).unwrap();
+            writer.write_all(b"Hello\nWorld\n").unwrap();
+            let compressed = writer.finish().unwrap();
+            assert_eq!(Compression::from_magic(&compressed), compression);
+
+            let mut decompressed = String::new();
+            decompress(io::Cursor::new(compressed.clone())).unwrap().read_to_string(&mut decompressed).unwrap();
+            assert_eq!(decompressed, "Hello\nWorld\n");
+
+            // A pipe that returns one byte per read.
+            let mut decompressed = String::new();
+            let pipe = BufReader::with_capacity(1, io::Cursor::new(compressed));
+            decompress(pipe).unwrap().read_to_string(&mut decompressed).unwrap();
+            assert_eq!(decompressed, "Hello\nWorld\n");
+        }
+    }
+
+    #[test]
+    fn test_compression_from_path() {
+        assert_eq!(Compression::from_path("corpus.en.gz"), Compression::Gzip);
+        assert_eq!(Compression::from_path("corpus.tmx.xz"), Compression::Xz);
+        assert_eq!(Compression::from_path("corpus.jsonl.zst"), Compression::Zstd);
+        assert_eq!(Compression::from_path("corpus.en"), Compression::None);
+    }
+}
@@ -1,20 +1,769 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::BTreeMap;
+use std::fmt;
 use std::fs;
+use std::path::{Path, PathBuf};
+use regex::Regex;
+use serde::Deserialize;
 use serde_yaml;
-use crate::cleaner::*;
+use serde_yaml::Value;
+use crate::cleaner::Cleaner;
+use crate::deduplicator::{ExternalDeduplicator, KeepPolicy, KeySide, KeyStore, ParallelDeduplicator, MAX_PARTITIONS};
+use crate::minhash::NearDeduplicator;
+use crate::contamination;
+use crate::contamination::{ContaminationFilter, TestSetFormat};
+use crate::filter;
+use crate::langid;
+use crate::langid::{DetectorOptions, LangIdAnnotator};
 use crate::model::BiText;
+use crate::normalize::Normalization;
 use phf::phf_map;
-use crate::filter::LengthFilterUnit;
+use crate::filter::{LengthFilterUnit, Side};
+use crate::step::{ScoringStep, Step};
 
-static COUNTRIES: phf::Map<&'static str, fn (Vec<BiText>)->Vec<BiText>> = phf_map! {
+pub(crate) static CLEANERS: phf::Map<&'static str, fn (Vec<BiText>)->Vec<BiText>> = phf_map! {
     "whitespace_cleaner" => crate::cleaner::whitespace_cleaner,
     "diacritics_cleaner" => crate::cleaner::diacritics_cleaner,
-    // "length_filter" => crate::filter::LengthRatioFilter { threshold: 1.2, unit: LengthFilterUnit::Char }:filter_text,
+    "html_cleaner" => crate::cleaner::html_cleaner,
 };
 
-pub(crate) fn parse_config(config: &str) -> BTreeMap<String,String>{
-    let config = fs::read_to_string(config).expect("Config not found");
-    let config: BTreeMap<String, String> = serde_yaml::from_str(&config).expect("Parsing failed");
-    config.keys().into_iter().for_each(|x| println!("{}", x));
-    config
-}
\ No newline at end of file
+type StepConstructor = fn(&StepConfig) -> Result<Box<dyn Step>, ConfigError>;
+
+/// Constructors of all steps that take parameters or keep state.
+pub(crate) static STEPS: phf::Map<&'static str, StepConstructor> = phf_map! {
+    "html_filter" => html_filter,
+    "source_deduplicator" => source_deduplicator,
+    "target_deduplicator" => target_deduplicator,
+    "pair_deduplicator" => pair_deduplicator,
+    "near_deduplicator" => near_deduplicator,
+    "length_filter" => length_filter,
+    "length_ratio_filter" => length_ratio_filter,
+    "long_word_filter" => long_word_filter,
+    "regexp_filter" => regexp_filter,
+    "langid_filter" => langid_filter,
+    "langid_annotator" => langid_annotator,
+    "similarity_filter" => similarity_filter,
+    "threshold_filter" => threshold_filter,
+    "contamination_filter" => contamination_filter,
+};
+
+/// Whether a filter removes pairs or only scores them, set with the `mode`
+/// parameter that every filter accepts. In score mode, `score_name` sets the
+/// name the scores are written under, which defaults to the name of the step.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StepMode {
+    #[default]
+    Filter,
+    Score,
+}
+
+/// Builds a step from the cleaners or the step registry.
+pub fn build_step(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let (step, mode, score_name) = step.split_mode()?;
+    let built = build_filter_step(&step)?;
+    match (mode, score_name) {
+        (StepMode::Filter, None) => Ok(built),
+        (StepMode::Filter, Some(_)) => Err(step.error(String::from("score_name is only used with mode: score"))),
+        (StepMode::Score, score_name) => match ScoringStep::new(built) {
+            Ok(scoring) => Ok(Box::new(scoring.with_score_name(&score_name.unwrap_or(step.name.clone())))),
+            Err(_) => Err(step.error(String::from("this step can not run in score mode"))),
+        },
+    }
+}
+
+fn build_filter_step(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    if let Some((name, cleaner)) = CLEANERS.get_entry(step.name.as_str()) {
+        step.no_params()?;
+        return Ok(Box::new(Cleaner::new(name, *cleaner)));
+    }
+    match STEPS.get(step.name.as_str()) {
+        Some(constructor) => constructor(step),
+        None => Err(step.error(format!("unknown step '{}'", step.name))),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(serde_yaml::Error),
+    InvalidStep { index: usize, name: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(source) => write!(f, "Could not read config: {}", source),
+            ConfigError::Parse(source) => write!(f, "Invalid config: {}", source),
+            ConfigError::InvalidStep { index, name, message } if name.is_empty() => {
+                write!(f, "Invalid config: step {}: {}", index + 1, message)
+            }
+            ConfigError::InvalidStep { index, name, message } => {
+                write!(f, "Invalid config: step {} ({}): {}", index + 1, name, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(source) => Some(source),
+            ConfigError::Parse(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// An ordered list of pipeline steps, e.g.
+///
+/// ```yaml
+/// steps:
+///   - whitespace_cleaner
+///   - length_filter: {min: 5, max: 40, unit: word}
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    steps: Vec<Value>,
+}
+
+/// A single step of a [`PipelineConfig`] with its still untyped parameters.
+#[derive(Debug, Clone)]
+pub struct StepConfig {
+    pub index: usize,
+    pub name: String,
+    pub params: Value,
+}
+
+impl StepConfig {
+    pub fn new(name: &str, params: Value) -> Self {
+        StepConfig { index: 0, name: String::from(name), params }
+    }
+
+    /// Deserializes the parameters of the step into the type the step expects.
+    pub fn params<T: for<'de> Deserialize<'de>>(&self) -> Result<T, ConfigError> {
+        let params = match &self.params {
+            Value::Null => Value::Mapping(Default::default()),
+            params => params.clone(),
+        };
+        serde_yaml::from_value(params).map_err(|x| self.error(x.to_string()))
+    }
+
+    /// Fails if parameters were given to a step that has none.
+    pub fn no_params(&self) -> Result<(), ConfigError> {
+        match &self.params {
+            Value::Null => Ok(()),
+            _ => Err(self.error(String::from("this step has no parameters"))),
+        }
+    }
+
+    /// Removes the `mode` and `score_name` parameters, which are handled for
+    /// all steps alike.
+    fn split_mode(&self) -> Result<(StepConfig, StepMode, Option<String>), ConfigError> {
+        let mut step = self.clone();
+        let (mode, score_name) = match &mut step.params {
+            Value::Mapping(params) => (params.remove("mode"), params.remove("score_name")),
+            _ => (None, None),
+        };
+        if matches!(&step.params, Value::Mapping(params) if params.is_empty()) {
+            step.params = Value::Null;
+        }
+        let mode = match mode {
+            Some(mode) => serde_yaml::from_value(mode).map_err(|x| self.error(x.to_string()))?,
+            None => StepMode::default(),
+        };
+        let score_name = match score_name {
+            Some(name) => Some(serde_yaml::from_value(name).map_err(|x| self.error(x.to_string()))?),
+            None => None,
+        };
+        Ok((step, mode, score_name))
+    }
+
+    /// The name the step writes its scores under, None if it does not run in score mode.
+    pub fn score_name(&self) -> Result<Option<String>, ConfigError> {
+        let (_, mode, score_name) = self.split_mode()?;
+        Ok(match mode {
+            StepMode::Score => Some(score_name.unwrap_or(self.name.clone())),
+            StepMode::Filter => None,
+        })
+    }
+
+    pub fn error(&self, message: String) -> ConfigError {
+        ConfigError::InvalidStep { index: self.index, name: self.name.clone(), message }
+    }
+}
+
+impl PipelineConfig {
+    pub fn from_str(config: &str) -> Result<Self, ConfigError> {
+        serde_yaml::from_str(config).map_err(ConfigError::Parse)
+    }
+
+    /// Steps are written either as a plain name or as a map with the name as
+    /// the only key and the parameters as value.
+    pub fn steps(&self) -> Result<Vec<StepConfig>, ConfigError> {
+        self.steps
+            .iter()
+            .enumerate()
+            .map(|(index, step)| match step {
+                Value::String(name) => Ok(StepConfig { index, name: name.clone(), params: Value::Null }),
+                Value::Mapping(map) if map.len() == 1 => {
+                    let (name, params) = map.iter().next().unwrap();
+                    match name {
+                        Value::String(name) => Ok(StepConfig { index, name: name.clone(), params: params.clone() }),
+                        _ => Err(ConfigError::InvalidStep {
+                            index,
+                            name: String::new(),
+                            message: String::from("the step name must be a string"),
+                        }),
+                    }
+                }
+                _ => Err(ConfigError::InvalidStep {
+                    index,
+                    name: String::new(),
+                    message: String::from("expected a step name or a map with the step name as its only key"),
+                }),
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DeduplicatorParams {
+    #[serde(flatten)]
+    pub normalization: Normalization,
+    #[serde(default)]
+    pub store: KeyStoreKind,
+    /// Only used for the Bloom filter.
+    #[serde(default = "default_false_positive_rate")]
+    pub false_positive_rate: f64,
+    /// Number of distinct keys the Bloom filter is sized for.
+    #[serde(default = "default_expected_keys")]
+    pub expected_keys: usize,
+    /// Policies other than first need `store: disk`, as they hold all pairs back.
+    #[serde(default)]
+    pub keep: KeepPolicyKind,
+    /// The filter whose score decides with `keep: score`.
+    pub score: Option<String>,
+    /// Number of files the pairs are split into with `store: disk`.
+    #[serde(default = "default_partitions")]
+    pub partitions: usize,
+    /// Where the partition files are written, the system temporary directory by default.
+    pub temp_dir: Option<String>,
+    /// Partition files larger than this are split further before they are
+    /// deduplicated, which bounds the memory per partition.
+    #[serde(default = "default_max_partition_mb")]
+    pub max_partition_mb: u64,
+    /// Number of partitions deduplicated at the same time, the number of threads by default.
+    pub parallel_partitions: Option<usize>,
+    /// Number of the largest groups of duplicates listed in the statistics report.
+    #[serde(default)]
+    pub clusters: usize,
+    /// Removed pairs shown per group of duplicates.
+    #[serde(default = "default_cluster_examples")]
+    pub cluster_examples: usize,
+}
+
+fn default_cluster_examples() -> usize {
+    3
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeepPolicyKind {
+    #[default]
+    First,
+    Last,
+    Longest,
+    Score,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NearDeduplicatorParams {
+    /// Pairs with at least this Jaccard similarity to an earlier pair are removed.
+    #[serde(default = "default_jaccard_threshold")]
+    pub threshold: f64,
+    #[serde(default)]
+    pub side: KeySide,
+    #[serde(default = "default_shingle_unit")]
+    pub unit: LengthFilterUnit,
+    #[serde(default = "default_shingle_size")]
+    pub shingle_size: usize,
+    #[serde(default = "default_num_hashes")]
+    pub num_hashes: usize,
+    #[serde(flatten)]
+    pub normalization: Normalization,
+    /// Number of the largest groups of near duplicates listed in the statistics report.
+    #[serde(default)]
+    pub clusters: usize,
+    /// Removed pairs shown per group of near duplicates.
+    #[serde(default = "default_cluster_examples")]
+    pub cluster_examples: usize,
+}
+
+fn default_jaccard_threshold() -> f64 {
+    0.8
+}
+
+fn default_shingle_unit() -> LengthFilterUnit {
+    LengthFilterUnit::Word
+}
+
+fn default_shingle_size() -> usize {
+    3
+}
+
+fn default_num_hashes() -> usize {
+    128
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyStoreKind {
+    #[default]
+    Exact,
+    Hash64,
+    Hash128,
+    Bloom,
+    /// Deduplicates partitions of the corpus on disk after the last chunk.
+    Disk,
+}
+
+fn default_partitions() -> usize {
+    256
+}
+
+fn default_max_partition_mb() -> u64 {
+    64
+}
+
+fn default_false_positive_rate() -> f64 {
+    0.001
+}
+
+fn default_expected_keys() -> usize {
+    10_000_000
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LengthFilterParams {
+    pub min: i16,
+    pub max: i16,
+    pub unit: LengthFilterUnit,
+    #[serde(default)]
+    pub side: Side,
+    /// Limits for the target side, `min` and `max` are used if they are not given.
+    pub trg_min: Option<i16>,
+    pub trg_max: Option<i16>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LengthRatioFilterParams {
+    pub threshold: f32,
+    pub unit: LengthFilterUnit,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LongWordFilterParams {
+    pub threshold: i16,
+    pub trg_threshold: Option<i16>,
+    #[serde(default)]
+    pub side: Side,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RegExpFilterParams {
+    pub regexp: String,
+    pub trg_regexp: Option<String>,
+    #[serde(default)]
+    pub accept: bool,
+    #[serde(default)]
+    pub side: Side,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LangIdFilterParams {
+    /// Languages for pairs that do not specify their own.
+    #[serde(alias = "lang")]
+    pub src_lang: Option<String>,
+    pub trg_lang: Option<String>,
+    #[serde(default = "default_min_confidence")]
+    pub min_confidence: f64,
+    /// Segments with fewer characters pass when no language is detected.
+    #[serde(default)]
+    pub short_length: usize,
+    /// Languages the detector chooses from, all languages if not given. The
+    /// source and target language are always included.
+    #[serde(default)]
+    pub languages: Vec<String>,
+    #[serde(default)]
+    pub low_accuracy: bool,
+    #[serde(default)]
+    pub preload: bool,
+}
+
+fn default_min_confidence() -> f64 {
+    0.5
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ThresholdFilterParams {
+    /// Lowest score to keep a pair, by filter name.
+    #[serde(default)]
+    pub min: BTreeMap<String, f64>,
+    /// Highest score to keep a pair, by filter name.
+    #[serde(default)]
+    pub max: BTreeMap<String, f64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TestSetParams {
+    pub file: String,
+    /// Names the test set in the reasons of removed pairs, the file name by default.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Guessed from the extension if not set.
+    #[serde(default)]
+    pub format: Option<TestSetFormat>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ContaminationFilterParams {
+    pub test_sets: Vec<TestSetParams>,
+    #[serde(default)]
+    pub side: Side,
+    /// Also removes pairs that share at least this fraction of their n-grams
+    /// with a held-out sentence, between 0 and 1.
+    #[serde(default)]
+    pub overlap: Option<f64>,
+    #[serde(default = "default_shingle_unit")]
+    pub unit: LengthFilterUnit,
+    #[serde(default = "default_ngram_size")]
+    pub ngram_size: usize,
+    #[serde(flatten)]
+    pub normalization: Normalization,
+}
+
+fn default_ngram_size() -> usize {
+    8
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LangIdAnnotatorParams {
+    /// Sets missing languages of pairs to the detected ones.
+    #[serde(default)]
+    pub fill_languages: bool,
+    #[serde(default)]
+    pub languages: Vec<String>,
+    #[serde(default)]
+    pub low_accuracy: bool,
+    #[serde(default)]
+    pub preload: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SimilarityFilterParams {
+    /// Pairs at least this similar are removed, between 0 and 1.
+    pub threshold: f32,
+    #[serde(default)]
+    pub unit: LengthFilterUnit,
+    #[serde(flatten)]
+    pub normalization: Normalization,
+}
+
+fn html_filter(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    step.no_params()?;
+    Ok(Box::new(filter::HtmlFilter::new()))
+}
+
+fn source_deduplicator(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    deduplicator(step, KeySide::Source)
+}
+
+fn target_deduplicator(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    deduplicator(step, KeySide::Target)
+}
+
+fn pair_deduplicator(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    deduplicator(step, KeySide::Pair)
+}
+
+fn deduplicator(step: &StepConfig, side: KeySide) -> Result<Box<dyn Step>, ConfigError> {
+    let params: DeduplicatorParams = step.params()?;
+    if params.false_positive_rate <= 0.0 || params.false_positive_rate >= 1.0 {
+        return Err(step.error(format!("false_positive_rate must be between 0 and 1, got {}", params.false_positive_rate)));
+    }
+    let keep = match (params.keep, params.score) {
+        (KeepPolicyKind::First, None) => KeepPolicy::First,
+        (KeepPolicyKind::Last, None) => KeepPolicy::Last,
+        (KeepPolicyKind::Longest, None) => KeepPolicy::Longest,
+        (KeepPolicyKind::Score, Some(score)) => KeepPolicy::HighestScore(score),
+        (KeepPolicyKind::Score, None) => return Err(step.error(String::from("keep: score needs the name of a score"))),
+        (_, Some(_)) => return Err(step.error(String::from("score is only used with keep: score"))),
+    };
+    let store = match params.store {
+        KeyStoreKind::Exact => KeyStore::Exact,
+        KeyStoreKind::Hash64 => KeyStore::Hash64,
+        KeyStoreKind::Hash128 => KeyStore::Hash128,
+        KeyStoreKind::Bloom => KeyStore::Bloom {
+            expected_keys: params.expected_keys,
+            false_positive_rate: params.false_positive_rate,
+        },
+        KeyStoreKind::Disk => {
+            if params.partitions == 0 || params.partitions > MAX_PARTITIONS {
+                return Err(step.error(format!("partitions must be between 1 and {}, got {}", MAX_PARTITIONS, params.partitions)));
+            }
+            let temp_dir = params.temp_dir.map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+            let deduplicator = ExternalDeduplicator::new(side, params.normalization, &temp_dir)
+                .map_err(|error| step.error(format!("could not create a directory in {}: {}", temp_dir.display(), error)))?;
+            let mut deduplicator = deduplicator
+                .with_keep(keep)
+                .with_partitions(params.partitions)
+                .with_max_partition_size(params.max_partition_mb.saturating_mul(1 << 20))
+                .with_cluster_report(params.clusters, params.cluster_examples);
+            if let Some(parallel_partitions) = params.parallel_partitions {
+                deduplicator = deduplicator.with_parallel_partitions(parallel_partitions);
+            }
+            return Ok(Box::new(deduplicator));
+        }
+    };
+    if keep != KeepPolicy::First {
+        return Err(step.error(String::from("keep policies other than first hold every unique pair back, use them with store: disk")));
+    }
+    let deduplicator = ParallelDeduplicator::new(side, params.normalization)
+        .with_store(store)
+        .with_cluster_report(params.clusters, params.cluster_examples);
+    Ok(Box::new(deduplicator))
+}
+
+fn near_deduplicator(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let params: NearDeduplicatorParams = step.params()?;
+    if params.threshold <= 0.0 || params.threshold > 1.0 {
+        return Err(step.error(format!("threshold must be between 0 and 1, got {}", params.threshold)));
+    }
+    if params.shingle_size == 0 || params.num_hashes == 0 {
+        return Err(step.error(String::from("shingle_size and num_hashes must be at least 1")));
+    }
+    let deduplicator = NearDeduplicator::new(params.threshold)
+        .with_side(params.side)
+        .with_shingles(params.unit, params.shingle_size)
+        .with_num_hashes(params.num_hashes)
+        .with_normalization(params.normalization)
+        .with_cluster_report(params.clusters, params.cluster_examples);
+    Ok(Box::new(deduplicator))
+}
+
+fn length_filter(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let params: LengthFilterParams = step.params()?;
+    let trg_min = params.trg_min.unwrap_or(params.min);
+    let trg_max = params.trg_max.unwrap_or(params.max);
+    if params.min > params.max {
+        return Err(step.error(format!("min ({}) is larger than max ({})", params.min, params.max)));
+    }
+    if trg_min > trg_max {
+        return Err(step.error(format!("trg_min ({}) is larger than trg_max ({})", trg_min, trg_max)));
+    }
+    let filter = filter::LengthFilter::new(params.min, params.max, params.unit)
+        .with_target_length(trg_min, trg_max)
+        .with_side(params.side);
+    Ok(Box::new(filter))
+}
+
+fn length_ratio_filter(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let params: LengthRatioFilterParams = step.params()?;
+    if !(0.0..=1.0).contains(&params.threshold) {
+        return Err(step.error(format!("threshold must be between 0 and 1, got {}", params.threshold)));
+    }
+    Ok(Box::new(filter::LengthRatioFilter::new(params.threshold, params.unit)))
+}
+
+fn long_word_filter(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let params: LongWordFilterParams = step.params()?;
+    let filter = filter::LongWordFilter::new(params.threshold)
+        .with_target_threshold(params.trg_threshold.unwrap_or(params.threshold))
+        .with_side(params.side);
+    Ok(Box::new(filter))
+}
+
+fn regexp_filter(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let params: RegExpFilterParams = step.params()?;
+    let trg_regexp = params.trg_regexp.as_deref().unwrap_or(&params.regexp);
+    for regexp in [params.regexp.as_str(), trg_regexp] {
+        Regex::new(regexp).map_err(|x| step.error(x.to_string()))?;
+    }
+    let filter = filter::RegExpFilter::new(&params.regexp, params.accept)
+        .with_target_regexp(trg_regexp)
+        .with_side(params.side);
+    Ok(Box::new(filter))
+}
+
+fn langid_filter(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let params: LangIdFilterParams = step.params()?;
+    let language = |lang: &str| langid::parse_language(lang).ok_or_else(|| step.error(format!("unknown language '{}'", lang)));
+    let src_lang = params.src_lang.as_deref().map(language).transpose()?;
+    let trg_lang = params.trg_lang.as_deref().map(language).transpose()?;
+    if !(0.0..=1.0).contains(&params.min_confidence) {
+        return Err(step.error(format!("min_confidence must be between 0 and 1, got {}", params.min_confidence)));
+    }
+    let mut options = DetectorOptions {
+        languages: params.languages.iter().map(|x| language(x)).collect::<Result<_, _>>()?,
+        low_accuracy: params.low_accuracy,
+        preload: params.preload,
+    };
+    if !options.languages.is_empty() {
+        options.languages.extend(src_lang.into_iter().chain(trg_lang));
+        if options.languages.len() < 2 {
+            return Err(step.error(String::from("languages must contain at least two languages")));
+        }
+    }
+    let filter = filter::LangIdFilter::new(src_lang, trg_lang, options)
+        .with_min_confidence(params.min_confidence)
+        .with_short_length(params.short_length);
+    Ok(Box::new(filter))
+}
+
+fn langid_annotator(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let params: LangIdAnnotatorParams = step.params()?;
+    let options = DetectorOptions {
+        languages: params
+            .languages
+            .iter()
+            .map(|x| langid::parse_language(x).ok_or_else(|| step.error(format!("unknown language '{}'", x))))
+            .collect::<Result<_, _>>()?,
+        low_accuracy: params.low_accuracy,
+        preload: params.preload,
+    };
+    if options.languages.len() == 1 {
+        return Err(step.error(String::from("languages must contain at least two languages")));
+    }
+    Ok(Box::new(LangIdAnnotator::new(&options).with_fill_languages(params.fill_languages)))
+}
+
+fn similarity_filter(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let params: SimilarityFilterParams = step.params()?;
+    if !(0.0..=1.0).contains(&params.threshold) {
+        return Err(step.error(format!("threshold must be between 0 and 1, got {}", params.threshold)));
+    }
+    Ok(Box::new(filter::SimilarityFilter::new(params.threshold, params.unit).with_normalization(params.normalization)))
+}
+
+fn threshold_filter(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let params: ThresholdFilterParams = step.params()?;
+    Ok(Box::new(filter::ThresholdFilter::new(params.min, params.max)))
+}
+
+fn contamination_filter(step: &StepConfig) -> Result<Box<dyn Step>, ConfigError> {
+    let params: ContaminationFilterParams = step.params()?;
+    if params.test_sets.is_empty() {
+        return Err(step.error(String::from("test_sets must list at least one file")));
+    }
+    if params.ngram_size == 0 {
+        return Err(step.error(String::from("ngram_size must be at least 1")));
+    }
+    let mut filter = ContaminationFilter::new(params.normalization).with_side(params.side);
+    if let Some(overlap) = params.overlap {
+        if overlap <= 0.0 || overlap > 1.0 {
+            return Err(step.error(format!("overlap must be between 0 and 1, got {}", overlap)));
+        }
+        filter = filter.with_ngram_overlap(params.unit, params.ngram_size, overlap);
+    }
+    for test_set in &params.test_sets {
+        let format = test_set.format.unwrap_or_else(|| TestSetFormat::from_path(&test_set.file));
+        let sentences = contamination::read_test_set(&test_set.file, format)
+            .map_err(|error| step.error(format!("could not read {}: {}", test_set.file, error)))?;
+        let name = test_set.name.as_deref().unwrap_or_else(|| {
+            Path::new(&test_set.file).file_name().and_then(|x| x.to_str()).unwrap_or(&test_set.file)
+        });
+        filter.add_test_set(name, sentences);
+    }
+    Ok(Box::new(filter))
+}
+
+pub(crate) fn parse_config(config: &str) -> Result<PipelineConfig, ConfigError> {
+    let config = fs::read_to_string(config).map_err(ConfigError::Io)?;
+    PipelineConfig::from_str(&config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_steps() {
+        let config = PipelineConfig::from_str(
+            "steps:\n  - whitespace_cleaner\n  - length_filter: {min: 5, max: 40, unit: word}\n",
+        )
+        .unwrap();
+        let steps = config.steps().unwrap();
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].name, "whitespace_cleaner");
+        let params: LengthFilterParams = steps[1].params().unwrap();
+        assert_eq!((params.min, params.max), (5, 40));
+        assert!(params.unit == LengthFilterUnit::Word);
+        assert_eq!(params.side, Side::Both);
+
+        let config = PipelineConfig::from_str(
+            "steps:\n  - length_filter: {min: 1, max: 40, unit: word, side: either, trg_max: 80}\n",
+        )
+        .unwrap();
+        let params: LengthFilterParams = config.steps().unwrap()[0].params().unwrap();
+        assert_eq!((params.side, params.trg_min, params.trg_max), (Side::Either, None, Some(80)));
+    }
+
+    #[test]
+    fn test_invalid_params() {
+        let config = PipelineConfig::from_str("steps:\n  - long_word_filter: {treshold: 5}\n").unwrap();
+        let steps = config.steps().unwrap();
+        let error = steps[0].params::<LongWordFilterParams>().unwrap_err().to_string();
+        assert!(error.starts_with("Invalid config: step 1 (long_word_filter): unknown field `treshold`"));
+
+        let config = PipelineConfig::from_str("steps:\n  - source_deduplicator: {lowercase: true, digits: true}\n  - source_deduplicator: {lowercse: true}\n").unwrap();
+        let steps = config.steps().unwrap();
+        let params: DeduplicatorParams = steps[0].params().unwrap();
+        assert_eq!(params.normalization, Normalization { lowercase: true, digits: true, ..Normalization::default() });
+        let error = steps[1].params::<DeduplicatorParams>().unwrap_err().to_string();
+        assert!(error.starts_with("Invalid config: step 2 (source_deduplicator): unknown field `lowercse`"));
+    }
+
+    #[test]
+    fn test_score_mode() {
+        let config = PipelineConfig::from_str(
+            "steps:\n  - long_word_filter: {threshold: 3, mode: score}\n  - regexp_filter: {regexp: 'x', mode: filter}\n  - threshold_filter: {max: {long_word_filter:source: 4}}\n",
+        )
+        .unwrap();
+        let steps: Vec<Box<dyn Step>> = config.steps().unwrap().iter().map(|x| build_step(x).unwrap()).collect();
+        let mut bitexts = vec![BiText::new(String::from("aaaa"), None, None, None), BiText::new(String::from("aaaaa"), None, None, None)];
+        for step in steps.iter() {
+            bitexts = step.process(bitexts, &mut Vec::new()).unwrap();
+        }
+        assert_eq!(bitexts.len(), 1);
+        assert_eq!(bitexts[0].scores.get("long_word_filter:source"), Some(&4.0));
+
+        let config = PipelineConfig::from_str("steps:\n  - whitespace_cleaner: {mode: score}\n").unwrap();
+        assert!(build_step(&config.steps().unwrap()[0]).is_err());
+
+        let config = PipelineConfig::from_str("steps:\n  - regexp_filter: {regexp: 'x', mode: score, score_name: has_x}\n").unwrap();
+        let bitexts = build_step(&config.steps().unwrap()[0]).unwrap().process(vec![BiText::new(String::from("x"), None, None, None)], &mut Vec::new()).unwrap();
+        assert_eq!(bitexts[0].scores.get("has_x"), Some(&0.0));
+        let config = PipelineConfig::from_str("steps:\n  - regexp_filter: {regexp: 'x', score_name: has_x}\n").unwrap();
+        assert!(build_step(&config.steps().unwrap()[0]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_step() {
+        let config = PipelineConfig::from_str("steps:\n  - a: 1\n    b: 2\n").unwrap();
+        assert!(config.steps().is_err());
+        assert!(PipelineConfig::from_str("step: []\n").is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::io::BufRead;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use quick_xml::escape::unescape;
+use regex::Regex;
+use serde::Deserialize;
+use unicode_segmentation::UnicodeSegmentation;
+use crate::compression;
+use rayon::prelude::*;
+use crate::filter::{Filter, LengthFilterUnit, Rejection, Score, Side};
+use crate::model::BiText;
+use crate::normalize::Normalization;
+use crate::tsv::{TsvFormat, TsvReader};
+
+/// The formats held-out sets are read from.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TestSetFormat {
+    /// One sentence per line, one file per language.
+    Moses,
+    /// Tab separated with the source and target in the first two columns.
+    Tsv,
+    /// The WMT format with one `<seg>` element per line.
+    Sgml,
+}
+
+impl TestSetFormat {
+    /// Guesses the format from the extension, ignoring a compression extension.
+    pub fn from_path(path: &str) -> Self {
+        let path = [".gz", ".xz", ".zst", ".zstd"]
+            .iter()
+            .find_map(|x| path.strip_suffix(x))
+            .unwrap_or(path);
+        match path.rsplit_once('.').map(|x| x.1) {
+            Some("sgm" | "sgml") => TestSetFormat::Sgml,
+            Some("tsv") => TestSetFormat::Tsv,
+            _ => TestSetFormat::Moses,
+        }
+    }
+}
+
+/// Reads the sentences of a held-out set from a possibly compressed file.
+pub fn read_test_set(file: &str, format: TestSetFormat) -> Result<Vec<String>, Box<dyn Error>> {
+    read_sentences(compression::open(file)?, format)
+}
+
+pub fn read_sentences<R: BufRead>(reader: R, format: TestSetFormat) -> Result<Vec<String>, Box<dyn Error>> {
+    match format {
+        TestSetFormat::Moses => Ok(reader.lines().collect::<Result<_, _>>()?),
+        TestSetFormat::Tsv => {
+            let mut sentences = Vec::new();
+            for bitext in TsvReader::new(reader, TsvFormat::default(), None, None) {
+                let bitext = bitext?;
+                sentences.push(bitext.text);
+                sentences.extend(bitext.translation);
+            }
+            Ok(sentences)
+        }
+        TestSetFormat::Sgml => {
+            let segment = Regex::new(r"(?i)<seg[^>]*>(.*)</seg>").unwrap();
+            let mut sentences = Vec::new();
+            for line in reader.lines() {
+                if let Some(captures) = segment.captures(&line?) {
+                    sentences.push(unescape(&captures[1])?.into_owned());
+                }
+            }
+            Ok(sentences)
+        }
+    }
+}
+
+/// Removes pairs that contain a sentence of a held-out dev or test set, so
+/// that the evaluation of a model trained on the corpus stays meaningful.
+///
+/// A side matches when it equals a held-out sentence after normalization
+/// or, if an overlap threshold is set, when at least that fraction of the
+/// n-grams of the shorter of the two texts also occurs in the other one.
+/// Texts shorter than an n-gram are only compared as a whole. The reason of
+/// a removal names the test set and the line of the sentence that matched.
+///
+/// All held-out sentences and their n-grams are kept in memory.
+pub struct ContaminationFilter {
+    side: Side,
+    normalization: Normalization,
+    unit: LengthFilterUnit,
+    ngram_size: usize,
+    overlap: Option<f64>,
+    test_sets: Vec<(String, AtomicUsize)>,
+    /// The test set and line of every held-out sentence.
+    sentences: Vec<(usize, usize)>,
+    exact: HashMap<String, usize>,
+    /// The held-out sentences that contain an n-gram, by its hash.
+    ngrams: HashMap<u64, Vec<usize>>,
+    ngram_counts: Vec<usize>,
+}
+
+struct Match {
+    sentence: usize,
+    overlap: f64,
+    exact: bool,
+}
+
+impl ContaminationFilter {
+    /// Test sets have to be added after the normalization and n-grams are configured.
+    pub fn new(normalization: Normalization) -> Self {
+        ContaminationFilter {
+            side: Side::Both,
+            normalization,
+            unit: LengthFilterUnit::Word,
+            ngram_size: 8,
+            overlap: None,
+            test_sets: Vec::new(),
+            sentences: Vec::new(),
+            exact: HashMap::new(),
+            ngrams: HashMap::new(),
+            ngram_counts: Vec::new(),
+        }
+    }
+
+    pub fn with_side(mut self, side: Side) -> Self {
+        self.side = side;
+        self
+    }
+
+    /// Also matches texts that share at least `overlap` of their n-grams of
+    /// `ngram_size` words or characters.
+    pub fn with_ngram_overlap(mut self, unit: LengthFilterUnit, ngram_size: usize, overlap: f64) -> Self {
+        self.unit = unit;
+        self.ngram_size = ngram_size;
+        self.overlap = Some(overlap);
+        self
+    }
+
+    pub fn add_test_set<I: IntoIterator<Item = String>>(&mut self, name: &str, sentences: I) {
+        let test_set = self.test_sets.len();
+        self.test_sets.push((String::from(name), AtomicUsize::new(0)));
+        for (line, sentence) in sentences.into_iter().enumerate() {
+            let text = self.normalization.apply(&sentence);
+            if text.is_empty() {
+                continue;
+            }
+            let index = self.sentences.len();
+            self.sentences.push((test_set, line + 1));
+            self.exact.entry(text).or_insert(index);
+            let ngrams = match self.overlap {
+                Some(_) => self.ngrams(&sentence),
+                None => HashSet::new(),
+            };
+            self.ngram_counts.push(ngrams.len());
+            for ngram in ngrams {
+                self.ngrams.entry(ngram).or_default().push(index);
+            }
+        }
+    }
+
+    fn ngrams(&self, text: &str) -> HashSet<u64> {
+        let text = self.normalization.apply(text);
+        let tokens: Vec<&str> = match self.unit {
+            LengthFilterUnit::Char => text.graphemes(true).collect(),
+            LengthFilterUnit::Word => text.split_whitespace().collect(),
+        };
+        tokens
+            .windows(self.ngram_size)
+            .map(|ngram| {
+                let mut hasher = DefaultHasher::new();
+                ngram.hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect()
+    }
+
+    /// Finds the held-out sentence that `text` overlaps most with.
+    fn find(&self, text: &str) -> Option<Match> {
+        if let Some(&sentence) = self.exact.get(&self.normalization.apply(text)) {
+            return Some(Match { sentence, overlap: 1.0, exact: true });
+        }
+        self.overlap?;
+        let ngrams = self.ngrams(text);
+        let mut shared: HashMap<usize, usize> = HashMap::new();
+        for sentence in ngrams.iter().filter_map(|x| self.ngrams.get(x)).flatten() {
+            *shared.entry(*sentence).or_default() += 1;
+        }
+        shared
+            .into_iter()
+            .map(|(sentence, shared)| {
+                let overlap = shared as f64 / ngrams.len().min(self.ngram_counts[sentence]) as f64;
+                Match { sentence, overlap, exact: false }
+            })
+            .max_by(|x, y| x.overlap.total_cmp(&y.overlap).then(y.sentence.cmp(&x.sentence)))
+    }
+
+    fn matches(&self, text: &str) -> Option<Match> {
+        self.find(text).filter(|x| x.overlap >= self.overlap.unwrap_or(1.0))
+    }
+
+    /// Why the pair is removed, with the held-out sets it matches, each once.
+    fn check(&self, bitext: &BiText) -> Option<(String, Vec<usize>)> {
+        let test_sets = RefCell::new(Vec::new());
+        let reason = self.side.reject_reason(bitext, |text, _| {
+            let found = self.matches(text)?;
+            let (test_set, line) = self.sentences[found.sentence];
+            test_sets.borrow_mut().push(test_set);
+            let name = &self.test_sets[test_set].0;
+            Some(match found.exact {
+                true => format!("matches {} line {}", name, line),
+                false => format!("overlaps {} line {} by {:.2}", name, line, found.overlap),
+            })
+        })?;
+        let mut test_sets = test_sets.into_inner();
+        test_sets.sort_unstable();
+        test_sets.dedup();
+        Some((reason, test_sets))
+    }
+}
+
+impl Filter for ContaminationFilter {
+    fn name(&self) -> &'static str {
+        "contamination_filter"
+    }
+
+    fn reject_reason(&self, bitext: &BiText) -> Option<String> {
+        self.check(bitext).map(|(reason, _)| reason)
+    }
+
+    /// Also counts the removed pairs per held-out set for [`Filter::details`].
+    fn filter_with_rejects(&self, texts: Vec<BiText>) -> (Vec<BiText>, Vec<Rejection>) {
+        let checked: Vec<_> = texts
+            .into_par_iter()
+            .map(|x| {
+                let found = self.check(&x);
+                (x, found)
+            })
+            .collect();
+        let mut kept = Vec::with_capacity(checked.len());
+        let mut rejected = Vec::new();
+        for (bitext, found) in checked {
+            match found {
+                Some((reason, test_sets)) => {
+                    for test_set in test_sets {
+                        self.test_sets[test_set].1.fetch_add(1, Ordering::Relaxed);
+                    }
+                    rejected.push(Rejection { bitext, filter: Filter::name(self), reason });
+                }
+                None => kept.push(bitext),
+            }
+        }
+        (kept, rejected)
+    }
+
+    /// The highest overlap with a held-out sentence, 1 for an exact match.
+    fn score(&self, bitext: &BiText) -> Option<Score> {
+        self.side
+            .score(bitext, false, |text, _| Some(self.find(text).map(|x| x.overlap).unwrap_or_default()))
+            .map(Score::Pair)
+    }
+
+    /// Pairs removed per held-out set. Nothing is removed in score mode, so
+    /// nothing is counted there.
+    fn details(&self) -> BTreeMap<String, f64> {
+        self.test_sets
+            .iter()
+            .map(|(name, removed)| (format!("removed:{}", name), removed.load(Ordering::Relaxed) as f64))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(text: &str, translation: &str) -> BiText {
+        BiText::new(String::from(text), None, Some(String::from(translation)), None)
+    }
+
+    #[test]
+    fn test_contamination_filter() {
+        let normalization = Normalization { lowercase: true, punctuation: true, ..Normalization::default() };
+        let mut filter = ContaminationFilter::new(normalization).with_ngram_overlap(LengthFilterUnit::Word, 3, 0.5);
+        filter.add_test_set("wmt22", vec![String::from("The cat sat on the mat."), String::new()]);
+        filter.add_test_set("flores", vec![String::from("Der Hund schläft im Garten hinter dem Haus")]);
+        assert_eq!(filter.sentences.len(), 2);
+
+        let exact = pair("the cat sat on the mat", "Die Katze saß auf der Matte");
+        assert_eq!(filter.reject_reason(&exact), Some(String::from("source matches wmt22 line 1")));
+        let overlap = pair("Yesterday", "Der Hund schläft im Garten hinter dem Haus am See");
+        assert_eq!(filter.reject_reason(&overlap), Some(String::from("target overlaps flores line 1 by 1.00")));
+        let clean = pair("The dog sat on a chair", "Der Hund saß auf einem Stuhl");
+        assert_eq!(filter.reject_reason(&clean), None);
+        assert_eq!(Filter::score(&filter, &clean), Some(Score::Pair(0.0)));
+        assert_eq!(filter.details()["removed:wmt22"], 0.0);
+
+        let (kept, rejected) = filter.filter_with_rejects(vec![exact, overlap, clean]);
+        assert_eq!((kept.len(), rejected.len()), (1, 2));
+        let details = filter.details();
+        assert_eq!(details["removed:wmt22"], 1.0);
+        assert_eq!(details["removed:flores"], 1.0);
+
+        let mut filter = ContaminationFilter::new(normalization).with_side(Side::Either);
+        filter.add_test_set("wmt22", vec![String::from("Hello"), String::from("Hallo")]);
+        assert_eq!(filter.filter_with_rejects(vec![pair("Hello", "Hallo")]).1.len(), 1);
+        assert_eq!(filter.details()["removed:wmt22"], 1.0);
+    }
+
+    #[test]
+    fn test_read_sentences() {
+        assert_eq!(TestSetFormat::from_path("newstest2019-ende-src.en.sgm.gz"), TestSetFormat::Sgml);
+        assert_eq!(TestSetFormat::from_path("flores.devtest.tsv"), TestSetFormat::Tsv);
+        assert_eq!(TestSetFormat::from_path("newstest2022.de"), TestSetFormat::Moses);
+
+        let sgml = "<srcset setid=\"newstest2019\">\n<doc docid=\"1\">\n<seg id=\"1\">Tom &amp; Jerry</seg>\n</doc>\n</srcset>\n";
+        let sentences = read_sentences(sgml.as_bytes(), TestSetFormat::Sgml).unwrap();
+        assert_eq!(sentences, vec![String::from("Tom & Jerry")]);
+        let sentences = read_sentences("Hallo\tHello\nTschüss\tBye\n".as_bytes(), TestSetFormat::Tsv).unwrap();
+        assert_eq!(sentences, vec!["Hallo", "Hello", "Tschüss", "Bye"]);
+    }
+}
@@ -0,0 +1,200 @@
+use std::error::Error;
+use std::io::Write;
+use std::str::FromStr;
+use crate::compression;
+use crate::compression::CompressedWriter;
+use crate::filter::Rejection;
+use crate::jsonl::{JsonlFields, JsonlReader, JsonlWriter};
+use crate::model::BiText;
+use crate::moses::{MismatchPolicy, MosesReader, MosesWriter};
+use crate::tmx::{TmxReader, TmxWriter};
+use crate::tsv::{TsvFormat, TsvReader, TsvWriter};
+
+/// The corpus formats that can be read and written.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    /// One file per language with one segment per line.
+    #[default]
+    Moses,
+    Tsv,
+    Csv,
+    Jsonl,
+    Tmx,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "moses" => Ok(Format::Moses),
+            "tsv" => Ok(Format::Tsv),
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            "tmx" => Ok(Format::Tmx),
+            _ => Err(format!("Unknown format '{}', expected moses, tsv, csv, jsonl or tmx", s)),
+        }
+    }
+}
+
+impl Format {
+    /// Guesses the format of a single file corpus from its extension, ignoring
+    /// a compression extension.
+    pub fn from_path(path: &str) -> Option<Self> {
+        let path = [".gz", ".xz", ".zst", ".zstd"]
+            .iter()
+            .find_map(|x| path.strip_suffix(x))
+            .unwrap_or(path);
+        let extension = path.rsplit_once('.')?.1;
+        match extension {
+            "moses" => None,
+            _ => extension.parse().ok(),
+        }
+    }
+}
+
+pub type BiTextReader = Box<dyn Iterator<Item = Result<BiText, Box<dyn Error>>>>;
+
+/// Opens a corpus in any of the supported formats.
+///
+/// `trg_file` is only used for Moses input. The languages fill in the
+/// language of every pair for which the input does not specify one.
+pub fn open_reader(
+    format: Format,
+    src_file: &str,
+    trg_file: Option<&str>,
+    src_lang: Option<String>,
+    trg_lang: Option<String>,
+    policy: MismatchPolicy,
+) -> Result<BiTextReader, Box<dyn Error>> {
+    let reader: BiTextReader = match format {
+        Format::Moses => {
+            let trg_file = trg_file.ok_or("Moses input needs a source and a target file")?;
+            let reader = MosesReader::open(src_file, trg_file, None, None)?.with_policy(policy);
+            Box::new(reader.map(|x| x.map_err(Box::from)))
+        }
+        Format::Tsv | Format::Csv => {
+            let tsv_format = if format == Format::Csv { TsvFormat::csv() } else { TsvFormat::default() };
+            let reader = TsvReader::open(src_file, tsv_format, None, None)?;
+            Box::new(reader.map(|x| x.map_err(Box::from)))
+        }
+        Format::Jsonl => {
+            let reader = JsonlReader::open(src_file, JsonlFields::default())?;
+            Box::new(reader.map(|x| x.map_err(Box::from)))
+        }
+        Format::Tmx => {
+            let reader = TmxReader::open(src_file)?.with_languages(src_lang.clone(), trg_lang.clone());
+            Box::new(reader.map(|x| x.map_err(Box::from)))
+        }
+    };
+    Ok(Box::new(reader.map(move |bitext| {
+        let mut bitext = bitext?;
+        if bitext.language.is_none() {
+            bitext.language = src_lang.clone();
+        }
+        if bitext.translation_language.is_none() {
+            bitext.translation_language = trg_lang.clone();
+        }
+        Ok(bitext)
+    })))
+}
+
+type Output = CompressedWriter<Box<dyn Write>>;
+
+/// Writes bitexts in any of the supported formats.
+pub enum BiTextWriter {
+    Moses(MosesWriter<Output, Output>),
+    Tsv(TsvWriter<Output>),
+    Jsonl(JsonlWriter<Output>),
+    Tmx(TmxWriter<Output>),
+}
+
+impl BiTextWriter {
+    /// Creates the source and target file of a Moses corpus.
+    pub fn create_moses(src_file: &str, trg_file: &str) -> Result<Self, Box<dyn Error>> {
+        let src = compression::create(src_file)?;
+        let trg = compression::create(trg_file)?;
+        Ok(BiTextWriter::Moses(MosesWriter::new(src, trg)))
+    }
+
+    /// Creates a single file corpus. `src_lang` is only used for the TMX header.
+    pub fn create(format: Format, file: &str, src_lang: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        Ok(match format {
+            Format::Moses => return Err("Moses output needs a source and a target file".into()),
+            Format::Tsv => BiTextWriter::Tsv(TsvWriter::new(compression::create(file)?, TsvFormat::default())),
+            Format::Csv => BiTextWriter::Tsv(TsvWriter::new(compression::create(file)?, TsvFormat::csv())),
+            Format::Jsonl => BiTextWriter::Jsonl(JsonlWriter::new(compression::create(file)?, JsonlFields::default())),
+            Format::Tmx => BiTextWriter::Tmx(TmxWriter::new(compression::create(file)?, src_lang)?),
+        })
+    }
+
+    pub fn write(&mut self, bitext: &BiText) -> Result<(), Box<dyn Error>> {
+        match self {
+            BiTextWriter::Moses(writer) => writer.write(bitext)?,
+            BiTextWriter::Tsv(writer) => writer.write(bitext)?,
+            BiTextWriter::Jsonl(writer) => writer.write(bitext)?,
+            BiTextWriter::Tmx(writer) => writer.write(bitext)?,
+        }
+        Ok(())
+    }
+
+    /// Flushes the output and completes compressed streams.
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            BiTextWriter::Moses(writer) => {
+                let (src, trg) = writer.finish()?;
+                src.finish()?;
+                trg.finish()?;
+            }
+            BiTextWriter::Tsv(writer) => {
+                writer.finish()?.finish()?;
+            }
+            BiTextWriter::Jsonl(writer) => {
+                writer.finish()?.finish()?;
+            }
+            BiTextWriter::Tmx(writer) => {
+                writer.finish()?.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes removed pairs for review as a TSV file with the columns filter,
+/// reason, source and target.
+pub struct RejectsWriter {
+    writer: TsvWriter<Output>,
+}
+
+impl RejectsWriter {
+    pub fn create(file: &str) -> Result<Self, Box<dyn Error>> {
+        let format = TsvFormat::default().with_columns(2, 3)?;
+        Ok(RejectsWriter { writer: TsvWriter::new(compression::create(file)?, format) })
+    }
+
+    pub fn write(&mut self, rejection: &Rejection) -> Result<(), Box<dyn Error>> {
+        let mut row = BiText::new(rejection.bitext.text.clone(), None, rejection.bitext.translation.clone(), None);
+        row.metadata.insert(String::from("column:0"), String::from(rejection.filter));
+        row.metadata.insert(String::from("column:1"), rejection.reason.clone());
+        self.writer.write(&row)?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        self.writer.finish()?.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(Format::from_path("corpus.jsonl.gz"), Some(Format::Jsonl));
+        assert_eq!(Format::from_path("corpus.tmx"), Some(Format::Tmx));
+        assert_eq!(Format::from_path("corpus.en"), None);
+        assert_eq!(Format::from_path("-"), None);
+    }
+}
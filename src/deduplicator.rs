@@ -1,91 +1,887 @@
-use std::collections::HashSet;
-use std::hash::Hash;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Lines, Write};
 use std::ops::Rem;
-use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
+use bloomfilter::Bloom;
+use serde::{Deserialize, Serialize};
+use crate::filter::Rejection;
 use crate::model::BiText;
-use rayon::iter::IntoParallelIterator;
+use crate::normalize::Normalization;
+use crate::stats::{DuplicateCluster, DuplicateExample};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator};
 use rayon::iter::ParallelIterator;
 
-pub trait Deduplicator{
-    fn deduplicate(bitext: Vec<BiText>, lowercase: bool) ->Vec<BiText>;
+/// Which part of a pair a deduplicator compares.
+#[derive(PartialEq, Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeySide {
+    Source,
+    Target,
+    /// Source and target together.
+    #[default]
+    Pair,
 }
 
-pub struct TargetDeduplicator{
-    cache: dyn Iterator<Item=BiText>
+impl KeySide {
+    /// Builds the key under which pairs are compared. A pair key starts with
+    /// the length of the source, so "ab" + "c" and "a" + "bc" differ.
+    pub fn key(self, bitext: &BiText, normalization: &Normalization) -> String {
+        let target = || normalization.apply(bitext.translation.as_deref().unwrap_or_default());
+        match self {
+            KeySide::Source => normalization.apply(&bitext.text),
+            KeySide::Target => target(),
+            KeySide::Pair => {
+                let source = normalization.apply(&bitext.text);
+                format!("{}:{}{}", source.len(), source, target())
+            }
+        }
+    }
+
+    /// The key shown in duplicate cluster reports, with source and target
+    /// separated by a tab.
+    pub(crate) fn readable_key(self, bitext: &BiText, normalization: &Normalization) -> String {
+        match self {
+            KeySide::Pair => {
+                let target = bitext.translation.as_deref().unwrap_or_default();
+                format!("{}\t{}", normalization.apply(&bitext.text), normalization.apply(target))
+            }
+            _ => self.key(bitext, normalization),
+        }
+    }
+
+    fn deduplicator_name(self) -> &'static str {
+        match self {
+            KeySide::Source => "source_deduplicator",
+            KeySide::Target => "target_deduplicator",
+            KeySide::Pair => "pair_deduplicator",
+        }
+    }
 }
 
-pub struct TargetDeduplicatorParallel{
-    cache: dyn Iterator<Item=BiText>
+/// Which pair of a group of duplicates is kept.
+///
+/// All policies but [`KeepPolicy::First`] have to see the whole group before
+/// they can keep a pair, which only [`ExternalDeduplicator`] does.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub enum KeepPolicy {
+    #[default]
+    First,
+    Last,
+    /// The pair with the most characters in source and target.
+    Longest,
+    /// The pair with the highest score of the given filter, see [`BiText::scores`].
+    HighestScore(String),
 }
 
+impl KeepPolicy {
+    /// The value the policy compares pairs by, so that deduplicators only
+    /// have to remember this value of the kept pair and not the pair itself.
+    fn rank(&self, bitext: &BiText) -> f64 {
+        match self {
+            KeepPolicy::First | KeepPolicy::Last => 0.0,
+            KeepPolicy::Longest => {
+                (bitext.text.chars().count() + bitext.translation.as_deref().unwrap_or_default().chars().count()) as f64
+            }
+            KeepPolicy::HighestScore(name) => bitext.scores.get(name).copied().unwrap_or(f64::NEG_INFINITY),
+        }
+    }
 
-pub struct RemoveDuplicates;
+    /// Whether a pair of rank `new` should replace the kept pair of rank
+    /// `kept`. Ties keep the earlier pair.
+    fn prefers(&self, new: f64, kept: f64) -> bool {
+        match self {
+            KeepPolicy::First => false,
+            KeepPolicy::Last => true,
+            KeepPolicy::Longest | KeepPolicy::HighestScore(_) => new > kept,
+        }
+    }
 
-impl Deduplicator for RemoveDuplicates {
-    fn deduplicate(bitext: Vec<BiText>, lowercase: bool) -> Vec<BiText> {
-        let mut hashset: HashSet<String> = HashSet::new();
-        let bitext = bitext.into_iter().filter(
-            |x| {
-                let val = x.translation.as_deref().unwrap_or_default();
-                let mut string: String = String::from(val) + &*x.text;
-                if lowercase{
-                    string = string.to_lowercase(); 
-                }
-                match hashset.contains(&*string) {
-                    true => { false }
-                    false => {
-                        hashset.insert(string);
-                        true
-                    }
-                }
+    fn describe(&self) -> String {
+        match self {
+            KeepPolicy::First => String::from("the first pair"),
+            KeepPolicy::Last => String::from("the last pair"),
+            KeepPolicy::Longest => String::from("the longest pair"),
+            KeepPolicy::HighestScore(name) => format!("the pair with the highest {} score", name),
+        }
+    }
+}
+
+/// Groups tracked per reported cluster by [`ClusterTracker`], and at least
+/// [`MIN_TRACKED_GROUPS`].
+const TRACKED_PER_CLUSTER: usize = 10;
+const MIN_TRACKED_GROUPS: usize = 1000;
+
+/// Estimates the largest groups of duplicates that a deduplicator removed
+/// pairs from, with a few of their removed pairs, in bounded memory.
+///
+/// Only a fixed number of groups is tracked, with the Space-Saving algorithm:
+/// a removed pair of an untracked group replaces the tracked group with the
+/// fewest removed pairs and takes over its count. Every group with more
+/// removed pairs than all removed pairs divided by the number of tracked
+/// groups is found, but the count of a group that replaced another one is
+/// overestimated by at most the count it took over, and its examples only
+/// start from there.
+pub(crate) struct ClusterTracker {
+    clusters: usize,
+    examples: usize,
+    capacity: usize,
+    tracked: Mutex<TrackedGroups>,
+}
+
+#[derive(Default)]
+struct TrackedGroups {
+    groups: HashMap<u64, TrackedGroup>,
+    /// Removed pairs and id of every tracked group, to find the smallest one.
+    by_removed: BTreeSet<(usize, u64)>,
+}
+
+struct TrackedGroup {
+    key: String,
+    removed: usize,
+    examples: Vec<DuplicateExample>,
+}
+
+impl ClusterTracker {
+    /// Returns None if no clusters are to be reported.
+    pub(crate) fn new(clusters: usize, examples: usize) -> Option<Self> {
+        (clusters > 0).then(|| ClusterTracker {
+            clusters,
+            examples,
+            capacity: clusters.saturating_mul(TRACKED_PER_CLUSTER).max(MIN_TRACKED_GROUPS),
+            tracked: Mutex::new(TrackedGroups::default()),
+        })
+    }
+
+    /// Adds a removed pair of the group with the id `group`. `key` names the
+    /// group in the report and is only called when the group is not tracked.
+    pub(crate) fn add(&self, group: u64, key: impl FnOnce() -> String, bitext: &BiText) {
+        let mut tracked = self.tracked.lock().unwrap();
+        let TrackedGroups { groups, by_removed } = &mut *tracked;
+        if let Some(tracked) = groups.get_mut(&group) {
+            by_removed.remove(&(tracked.removed, group));
+            tracked.removed += 1;
+            by_removed.insert((tracked.removed, group));
+            if tracked.examples.len() < self.examples {
+                tracked.examples.push(DuplicateExample::new(bitext));
             }
-        ).collect();
-        bitext
+            return;
+        }
+        let mut removed = 0;
+        if groups.len() >= self.capacity {
+            if let Some((smallest, replaced)) = by_removed.pop_first() {
+                groups.remove(&replaced);
+                removed = smallest;
+            }
+        }
+        let examples = match self.examples {
+            0 => Vec::new(),
+            _ => vec![DuplicateExample::new(bitext)],
+        };
+        groups.insert(group, TrackedGroup { key: key(), removed: removed + 1, examples });
+        by_removed.insert((removed + 1, group));
+    }
+
+    pub(crate) fn largest(&self) -> Vec<DuplicateCluster> {
+        let tracked = self.tracked.lock().unwrap();
+        let mut largest: Vec<&TrackedGroup> = tracked.groups.values().collect();
+        largest.sort_by(|x, y| y.removed.cmp(&x.removed).then_with(|| x.key.cmp(&y.key)));
+        largest
+            .into_iter()
+            .take(self.clusters)
+            .map(|group| DuplicateCluster {
+                key: group.key.clone(),
+                count: group.removed + 1,
+                examples: group.examples.clone(),
+            })
+            .collect()
+    }
+}
+
+/// The largest groups of duplicates, for deduplicators that know the size of
+/// every group. Only the `clusters` largest groups seen so far are kept.
+struct LargestClusters {
+    clusters: usize,
+    examples: usize,
+    /// The smallest of the kept groups is on top.
+    largest: Mutex<BinaryHeap<RankedCluster>>,
+}
+
+/// Orders clusters by number of pairs, the smallest first, and then by key
+/// in reverse.
+struct RankedCluster(DuplicateCluster);
+
+impl Ord for RankedCluster {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other.0.count.cmp(&self.0.count).then_with(|| self.0.key.cmp(&other.0.key))
+    }
+}
+
+impl PartialOrd for RankedCluster {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankedCluster {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for RankedCluster {}
+
+impl LargestClusters {
+    /// Returns None if no clusters are to be reported.
+    fn new(clusters: usize, examples: usize) -> Option<Self> {
+        (clusters > 0).then(|| LargestClusters { clusters, examples, largest: Mutex::new(BinaryHeap::new()) })
+    }
+
+    fn add(&self, cluster: DuplicateCluster) {
+        let mut largest = self.largest.lock().unwrap();
+        largest.push(RankedCluster(cluster));
+        if largest.len() > self.clusters {
+            largest.pop();
+        }
+    }
+
+    fn largest(&self) -> Vec<DuplicateCluster> {
+        let largest = self.largest.lock().unwrap();
+        let mut clusters: Vec<DuplicateCluster> = largest.iter().map(|x| x.0.clone()).collect();
+        clusters.sort_by(|x, y| y.count.cmp(&x.count).then_with(|| x.key.cmp(&y.key)));
+        clusters
+    }
+}
+
+/// How a deduplicator remembers the keys it has seen.
+///
+/// Everything but `Exact` trades memory for a small chance of removing a
+/// pair that is not a duplicate, see [`ParallelDeduplicator::false_positive_rate`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyStore {
+    /// The full keys.
+    Exact,
+    /// 64 bit hashes of the keys.
+    Hash64,
+    /// 128 bit hashes of the keys.
+    Hash128,
+    /// A Bloom filter sized for `expected_keys` with the given false positive rate.
+    Bloom { expected_keys: usize, false_positive_rate: f64 },
+}
+
+enum SeenKeys {
+    Exact(HashSet<String>),
+    Hash64(HashSet<u64>),
+    Hash128(HashSet<u128>),
+    Bloom { filter: Bloom<String>, keys: usize },
+}
+
+impl SeenKeys {
+    fn new(store: KeyStore, shards: usize) -> Self {
+        match store {
+            KeyStore::Exact => SeenKeys::Exact(HashSet::new()),
+            KeyStore::Hash64 => SeenKeys::Hash64(HashSet::new()),
+            KeyStore::Hash128 => SeenKeys::Hash128(HashSet::new()),
+            KeyStore::Bloom { expected_keys, false_positive_rate } => SeenKeys::Bloom {
+                filter: Bloom::new_for_fp_rate((expected_keys / shards).max(1), false_positive_rate),
+                keys: 0,
+            },
+        }
+    }
+
+    /// Returns whether the key was not seen before.
+    fn insert(&mut self, key: String, hash: u64) -> bool {
+        match self {
+            SeenKeys::Exact(seen) => seen.insert(key),
+            SeenKeys::Hash64(seen) => seen.insert(hash),
+            SeenKeys::Hash128(seen) => seen.insert((hash as u128) << 64 | key_hash(&key, 1) as u128),
+            SeenKeys::Bloom { filter, keys } => {
+                let new = !filter.check_and_set(&key);
+                *keys += usize::from(new);
+                new
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            SeenKeys::Exact(seen) => seen.len(),
+            SeenKeys::Hash64(seen) => seen.len(),
+            SeenKeys::Hash128(seen) => seen.len(),
+            SeenKeys::Bloom { keys, .. } => *keys,
+        }
+    }
+
+    /// Chance that a new key for this shard is taken for one that was seen.
+    /// Keys of one shard share the hash modulo the number of shards, which
+    /// leaves fewer hash bits to tell them apart.
+    fn false_positive_rate(&self, shards: usize) -> f64 {
+        match self {
+            SeenKeys::Exact(_) => 0.0,
+            SeenKeys::Hash64(seen) => seen.len() as f64 * shards as f64 / 2f64.powi(64),
+            SeenKeys::Hash128(seen) => seen.len() as f64 * shards as f64 / 2f64.powi(128),
+            SeenKeys::Bloom { filter, keys } => {
+                let bits = filter.number_of_bits() as f64;
+                let hashes = filter.number_of_hash_functions() as f64;
+                (1.0 - (-hashes * *keys as f64 / bits).exp()).powf(hashes)
+            }
+        }
+    }
+}
+
+fn key_hash(key: &str, seed: u8) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    seed.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Deduplicates on the source, the target or the whole pair, with the seen
+/// keys split by hash into shards that are checked in parallel.
+///
+/// Every text only ever goes to one shard, and each shard checks its pairs in
+/// input order, so the first occurrence is always the one that is kept.
+pub struct ParallelDeduplicator{
+    shards: Vec<Mutex<SeenKeys>>,
+    side: KeySide,
+    normalization: Normalization,
+    /// Number of pairs deduplicated so far.
+    pairs: AtomicUsize,
+    clusters: Option<ClusterTracker>,
+}
+
+impl ParallelDeduplicator {
+    /// Texts are compared after normalizing them.
+    pub fn new(side: KeySide, normalization: Normalization) -> Self {
+        ParallelDeduplicator {
+            shards: Vec::new(),
+            side,
+            normalization,
+            pairs: AtomicUsize::new(0),
+            clusters: None,
+        }
+        .with_store(KeyStore::Exact)
+    }
+
+    /// Collects the groups of duplicates, so that the `clusters` largest ones
+    /// can be reported with up to `examples` of their removed pairs, see
+    /// [`ParallelDeduplicator::largest_clusters`].
+    ///
+    /// Only a bounded number of groups is tracked, so the counts of the
+    /// reported groups may be overestimated, see [`ClusterTracker`].
+    pub fn with_cluster_report(mut self, clusters: usize, examples: usize) -> Self {
+        self.clusters = ClusterTracker::new(clusters, examples);
+        self
+    }
+
+    pub fn reports_clusters(&self) -> bool {
+        self.clusters.is_some()
+    }
+
+    /// The largest groups of duplicates found so far, by number of pairs.
+    pub fn largest_clusters(&self) -> Vec<DuplicateCluster> {
+        self.clusters.as_ref().map(|x| x.largest()).unwrap_or_default()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.side.deduplicator_name()
+    }
+
+    /// `hash` is the hash of the key of the pair.
+    fn rejection(&self, bitext: BiText, hash: u64) -> Rejection {
+        if let Some(clusters) = &self.clusters {
+            clusters.add(hash, || self.side.readable_key(&bitext, &self.normalization), &bitext);
+        }
+        Rejection { bitext, filter: self.name(), reason: format!("duplicate, keeping {}", KeepPolicy::First.describe()) }
+    }
+
+    /// Forgets all seen keys and stores them in `store` from now on.
+    pub fn with_store(mut self, store: KeyStore) -> Self {
+        let shards = rayon::current_num_threads() * 4;
+        self.shards = (0..shards).map(|_| Mutex::new(SeenKeys::new(store, shards))).collect();
+        self
+    }
+
+    /// Number of distinct keys seen so far.
+    pub fn seen_keys(&self) -> usize {
+        self.shards.iter().map(|x| x.lock().unwrap().len()).sum()
+    }
+
+    /// Chance that the next unique pair is removed as a duplicate.
+    pub fn false_positive_rate(&self) -> f64 {
+        let rates: f64 = self.shards.iter().map(|x| x.lock().unwrap().false_positive_rate(self.shards.len())).sum();
+        rates / self.shards.len() as f64
+    }
+
+    /// Deduplicates one chunk of a corpus, remembering what was seen in
+    /// earlier chunks so duplicates are removed across chunk boundaries.
+    /// Returns the kept and the removed pairs.
+    pub fn deduplicate_chunk(&self, bitext: Vec<BiText>) -> (Vec<BiText>, Vec<Rejection>) {
+        self.pairs.fetch_add(bitext.len(), Ordering::Relaxed);
+        let keys: Vec<(u64, String)> = bitext
+            .par_iter()
+            .map(|x| {
+                let key = self.side.key(x, &self.normalization);
+                (key_hash(&key, 0), key)
+            })
+            .collect();
+        let hashes: Vec<u64> = keys.iter().map(|(hash, _)| *hash).collect();
+        let mut by_shard: Vec<Vec<(usize, u64, String)>> = (0..self.shards.len()).map(|_| Vec::new()).collect();
+        for (index, (hash, key)) in keys.into_iter().enumerate() {
+            by_shard[(hash as usize).rem(self.shards.len())].push((index, hash, key));
+        }
+        let keep: Vec<bool> = {
+            let mut keep = vec![false; bitext.len()];
+            let kept: Vec<Vec<usize>> = by_shard
+                .into_par_iter()
+                .enumerate()
+                .map(|(shard, keys)| {
+                    let mut seen = self.shards[shard].lock().unwrap();
+                    keys.into_iter().filter_map(|(index, hash, key)| seen.insert(key, hash).then_some(index)).collect()
+                })
+                .collect();
+            kept.into_iter().flatten().for_each(|index| keep[index] = true);
+            keep
+        };
+        let mut kept = Vec::with_capacity(bitext.len());
+        let mut rejected = Vec::new();
+        for ((bitext, keep), hash) in bitext.into_iter().zip(keep).zip(hashes) {
+            match keep {
+                true => kept.push(bitext),
+                false => rejected.push(self.rejection(bitext, hash)),
+            }
+        }
+        (kept, rejected)
+    }
+}
+
+/// Deduplicates corpora that do not fit in memory.
+///
+/// Pairs are spilled to temporary files, partitioned by the hash of their key
+/// so that all duplicates of a pair end up in the same partition. After the
+/// last chunk, every partition is deduplicated on its own and the kept pairs
+/// of all partitions are merged back in input order, in batches of
+/// [`MERGE_BATCH_SIZE`].
+///
+/// Deduplicating a partition keeps a 128 bit hash of the key and a few
+/// numbers per group in memory, and the kept pairs that move to the position
+/// of an earlier pair of their group. Partition files larger than
+/// [`ExternalDeduplicator::with_max_partition_size`] are split further by
+/// another hash first, and only
+/// [`ExternalDeduplicator::with_parallel_partitions`] partitions are
+/// deduplicated at a time, which bounds the memory used.
+///
+/// All pairs are held back until the end of the corpus. Like with
+/// [`ParallelDeduplicator`], a group of duplicates is kept at the position of
+/// its first pair.
+pub struct ExternalDeduplicator {
+    side: KeySide,
+    normalization: Normalization,
+    keep: KeepPolicy,
+    partitions: usize,
+    max_partition_size: u64,
+    parallel_partitions: usize,
+    dir: PathBuf,
+    state: Mutex<SpillState>,
+    clusters: Option<LargestClusters>,
+}
+
+/// Pairs merged back per call of [`ExternalDeduplicator::finish`].
+pub const MERGE_BATCH_SIZE: usize = 10_000;
+
+/// Every partition has a file open while pairs are spilled and merged, so
+/// more partitions could exceed the limit of open files of the process.
+pub const MAX_PARTITIONS: usize = 512;
+
+/// How often a partition file is split again when it is too large. Pairs of
+/// one group always end up in the same file, so a single huge group cannot
+/// be split.
+const MAX_SPLIT_DEPTH: u8 = 3;
+
+/// A pair in a partition file, with its position in the input.
+#[derive(Serialize, Deserialize)]
+struct SpilledPair {
+    order: u64,
+    #[serde(default)]
+    duplicate: bool,
+    bitext: BiText,
+}
+
+enum SpillState {
+    Empty,
+    Spilling { next: u64, writers: Vec<BufWriter<File>> },
+    Merging(SpilledMerge),
+    /// Spilling or merging failed, so pairs were lost and every later call
+    /// returns the error again.
+    Failed { kind: io::ErrorKind, message: String },
+}
+
+impl SpillState {
+    /// Moves to `Failed` if `result` is an error.
+    fn fail_on<T>(&mut self, result: io::Result<T>) -> io::Result<T> {
+        if let Err(error) = &result {
+            *self = SpillState::Failed { kind: error.kind(), message: error.to_string() };
+        }
+        result
+    }
+
+    fn check_failed(&self) -> io::Result<()> {
+        match self {
+            SpillState::Failed { kind, message } => Err(io::Error::new(*kind, message.clone())),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The pair kept so far for a key of a partition.
+struct PartitionGroup {
+    first: u64,
+    kept_order: u64,
+    /// See [`KeepPolicy::rank`].
+    kept_rank: f64,
+    /// Number of pairs with the key.
+    count: usize,
+    /// The key and the first removed pairs, if clusters are reported.
+    key: Option<String>,
+    examples: Vec<DuplicateExample>,
+}
+
+/// Merges files of spilled pairs, each in input order, into one sequence in
+/// input order.
+struct SpilledMerge {
+    readers: Vec<Lines<BufReader<File>>>,
+    heads: BinaryHeap<Reverse<(u64, usize)>>,
+    pending: Vec<Option<SpilledPair>>,
+}
+
+impl SpilledMerge {
+    fn open(paths: &[PathBuf]) -> io::Result<Self> {
+        let mut merge = SpilledMerge { readers: Vec::new(), heads: BinaryHeap::new(), pending: Vec::new() };
+        for (index, path) in paths.iter().enumerate() {
+            let mut reader = BufReader::new(File::open(path)?).lines();
+            let head = next_spilled(&mut reader)?;
+            if let Some(pair) = &head {
+                merge.heads.push(Reverse((pair.order, index)));
+            }
+            merge.readers.push(reader);
+            merge.pending.push(head);
+        }
+        Ok(merge)
+    }
+
+    fn next(&mut self) -> io::Result<Option<SpilledPair>> {
+        let Some(Reverse((_, index))) = self.heads.pop() else { return Ok(None) };
+        let pair = self.pending[index].take();
+        self.pending[index] = next_spilled(&mut self.readers[index])?;
+        if let Some(next) = &self.pending[index] {
+            self.heads.push(Reverse((next.order, index)));
+        }
+        Ok(pair)
     }
 }
 
-impl Deduplicator for TargetDeduplicator {
-    fn deduplicate(bitext: Vec<BiText>, lowercase:bool) -> Vec<BiText> {
-        let mut hashset: HashSet<String> = HashSet::new();
-        let bitext = bitext.
-            into_iter().
-            filter(|x| {
-                match hashset.contains(&*x.text) {
-                    true => { false }
-                    false => {
-                        hashset.insert(String::from(x.text.clone()));
-                        true
+static EXTERNAL_DIRS: AtomicUsize = AtomicUsize::new(0);
+
+impl ExternalDeduplicator {
+    /// Creates a directory for the partition files in `temp_dir`, which is
+    /// removed again when the deduplicator is dropped.
+    pub fn new(side: KeySide, normalization: Normalization, temp_dir: &Path) -> io::Result<Self> {
+        let dir = temp_dir.join(format!(
+            "bitextcleaner-dedup-{}-{}",
+            std::process::id(),
+            EXTERNAL_DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir)?;
+        Ok(ExternalDeduplicator {
+            side,
+            normalization,
+            keep: KeepPolicy::First,
+            partitions: 256,
+            max_partition_size: 64 << 20,
+            parallel_partitions: rayon::current_num_threads(),
+            dir,
+            state: Mutex::new(SpillState::Empty),
+            clusters: None,
+        })
+    }
+
+    pub fn with_keep(mut self, keep: KeepPolicy) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// At most [`MAX_PARTITIONS`] are used.
+    pub fn with_partitions(mut self, partitions: usize) -> Self {
+        self.partitions = partitions.clamp(1, MAX_PARTITIONS);
+        self
+    }
+
+    /// Partition files of more than `bytes` are split into smaller files
+    /// before they are deduplicated, up to [`MAX_SPLIT_DEPTH`] times.
+    pub fn with_max_partition_size(mut self, bytes: u64) -> Self {
+        self.max_partition_size = bytes.max(1);
+        self
+    }
+
+    /// Number of partitions deduplicated at the same time, the number of
+    /// threads by default.
+    pub fn with_parallel_partitions(mut self, partitions: usize) -> Self {
+        self.parallel_partitions = partitions.max(1);
+        self
+    }
+
+    /// See [`ParallelDeduplicator::with_cluster_report`]. The size of every
+    /// group is known once the partitions are deduplicated, so the counts are
+    /// exact.
+    pub fn with_cluster_report(mut self, clusters: usize, examples: usize) -> Self {
+        self.clusters = LargestClusters::new(clusters, examples);
+        self
+    }
+
+    pub fn reports_clusters(&self) -> bool {
+        self.clusters.is_some()
+    }
+
+    /// The largest groups of duplicates, once the first call of
+    /// [`ExternalDeduplicator::finish`] deduplicated the partitions.
+    pub fn largest_clusters(&self) -> Vec<DuplicateCluster> {
+        self.clusters.as_ref().map(|x| x.largest()).unwrap_or_default()
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.side.deduplicator_name()
+    }
+
+    fn rejection(&self, bitext: BiText) -> Rejection {
+        Rejection { bitext, filter: self.name(), reason: format!("duplicate, keeping {}", self.keep.describe()) }
+    }
+
+    fn partition_file(&self, partition: usize, kept: bool) -> PathBuf {
+        match kept {
+            true => self.dir.join(format!("{}.kept.jsonl", partition)),
+            false => self.dir.join(format!("{}.jsonl", partition)),
+        }
+    }
+
+    /// Writes a chunk of a corpus to the partition files.
+    pub fn spill_chunk(&self, bitext: Vec<BiText>) -> io::Result<()> {
+        let keys: Vec<u64> = bitext.par_iter().map(|x| key_hash(&self.side.key(x, &self.normalization), 0)).collect();
+        let mut state = self.state.lock().unwrap();
+        state.check_failed()?;
+        let result = self.spill_locked(&mut state, bitext, keys);
+        state.fail_on(result)
+    }
+
+    fn spill_locked(&self, state: &mut SpillState, bitext: Vec<BiText>, keys: Vec<u64>) -> io::Result<()> {
+        if !matches!(*state, SpillState::Spilling { .. }) {
+            let writers = (0..self.partitions)
+                .map(|partition| Ok(BufWriter::new(File::create(self.partition_file(partition, false))?)))
+                .collect::<io::Result<_>>()?;
+            *state = SpillState::Spilling { next: 0, writers };
+        }
+        let SpillState::Spilling { next, writers } = state else { unreachable!() };
+        for (bitext, key) in bitext.into_iter().zip(keys) {
+            let pair = SpilledPair { order: *next, duplicate: false, bitext };
+            write_spilled(&mut writers[(key as usize).rem(self.partitions)], &pair)?;
+            *next += 1;
+        }
+        Ok(())
+    }
+
+    /// Deduplicates one partition file into a file with the kept pairs and the
+    /// duplicates marked, both in input order.
+    fn deduplicate_partition(&self, partition: usize) -> io::Result<()> {
+        self.deduplicate_file(&self.partition_file(partition, false), &self.partition_file(partition, true), 0)
+    }
+
+    /// Splits `path` into smaller files by another hash of the key while it is
+    /// larger than the maximum partition size, and merges the kept pairs of
+    /// the smaller files back into `kept_path`.
+    fn deduplicate_file(&self, path: &Path, kept_path: &Path, depth: u8) -> io::Result<()> {
+        let size = fs::metadata(path)?.len();
+        if size <= self.max_partition_size || depth >= MAX_SPLIT_DEPTH {
+            return self.deduplicate_groups(path, kept_path);
+        }
+        let parts = (size / self.max_partition_size + 1).min(MAX_PARTITIONS as u64) as usize;
+        let paths: Vec<PathBuf> = (0..parts).map(|part| path.with_extension(format!("{}.jsonl", part))).collect();
+        let mut writers = paths.iter().map(|x| Ok(BufWriter::new(File::create(x)?))).collect::<io::Result<Vec<_>>>()?;
+        for pair in read_spilled(path)? {
+            let pair = pair?;
+            let hash = key_hash(&self.side.key(&pair.bitext, &self.normalization), 2 + depth);
+            write_spilled(&mut writers[(hash as usize).rem(parts)], &pair)?;
+        }
+        for writer in writers.iter_mut() {
+            writer.flush()?;
+        }
+        drop(writers);
+        fs::remove_file(path)?;
+        let kept_paths: Vec<PathBuf> = paths.iter().map(|x| x.with_extension("kept.jsonl")).collect();
+        for (path, kept_path) in paths.iter().zip(kept_paths.iter()) {
+            self.deduplicate_file(path, kept_path, depth + 1)?;
+        }
+        let mut merge = SpilledMerge::open(&kept_paths)?;
+        let mut writer = BufWriter::new(File::create(kept_path)?);
+        while let Some(pair) = merge.next()? {
+            write_spilled(&mut writer, &pair)?;
+        }
+        writer.flush()?;
+        kept_paths.iter().try_for_each(fs::remove_file)
+    }
+
+    /// The id of the group of a pair. Keys are only kept as 128 bit hashes,
+    /// which are practically never equal for different keys.
+    fn group_id(&self, bitext: &BiText) -> u128 {
+        let key = self.side.key(bitext, &self.normalization);
+        (key_hash(&key, 0) as u128) << 64 | key_hash(&key, 1) as u128
+    }
+
+    /// Deduplicates a file of spilled pairs in memory, see [`ExternalDeduplicator::deduplicate_partition`].
+    fn deduplicate_groups(&self, path: &Path, kept_path: &Path) -> io::Result<()> {
+        let changed = || io::Error::new(io::ErrorKind::InvalidData, format!("{} changed while it was deduplicated", path.display()));
+        let mut groups: HashMap<u128, PartitionGroup> = HashMap::new();
+        for pair in read_spilled(path)? {
+            let pair = pair?;
+            let rank = self.keep.rank(&pair.bitext);
+            match groups.entry(self.group_id(&pair.bitext)) {
+                Entry::Occupied(mut group) => {
+                    let group = group.get_mut();
+                    group.count += 1;
+                    if self.keep.prefers(rank, group.kept_rank) {
+                        group.kept_order = pair.order;
+                        group.kept_rank = rank;
                     }
                 }
-            }).collect();
-        bitext
-    }
-}
-
-impl Deduplicator for TargetDeduplicatorParallel {
-    // Not working, as we have deadlocks. I know why, but are not sure how to best solve it yet.
-    // As this isn't really necessary to have yet, I'll ignore it for now.
-    fn deduplicate(bitext: Vec<BiText>, lowercase: bool) -> Vec<BiText> {
-        let hashset: RwLock<HashSet<String>> = RwLock::new(HashSet::new());
-        println!("{}", "here");
-
-        let bitext = bitext.
-            into_par_iter().
-            filter(|x| {
-                let reader = hashset.read();
-                println!("{}", "here");
-                match reader.unwrap().contains(&*x.text) {
-                    true =>{
-                        false
-                    }
-                    false => {
-                        hashset.write().unwrap().insert(x.text.clone());
-                        true
+                Entry::Vacant(group) => {
+                    group.insert(PartitionGroup {
+                        first: pair.order,
+                        kept_order: pair.order,
+                        kept_rank: rank,
+                        count: 1,
+                        key: None,
+                        examples: Vec::new(),
+                    });
+                }
+            }
+        }
+        // Kept pairs that are written at the position of the first pair of
+        // their group, which comes before them.
+        let moving: HashSet<u64> = groups.values().filter(|x| x.kept_order != x.first).map(|x| x.kept_order).collect();
+        let mut moved: HashMap<u64, BiText> = HashMap::new();
+        if !moving.is_empty() {
+            for pair in read_spilled(path)? {
+                let pair = pair?;
+                if moving.contains(&pair.order) {
+                    moved.insert(pair.order, pair.bitext);
+                }
+            }
+        }
+        let examples = self.clusters.as_ref().map(|x| x.examples).unwrap_or_default();
+        let mut writer = BufWriter::new(File::create(kept_path)?);
+        for pair in read_spilled(path)? {
+            let mut pair = pair?;
+            let group = groups.get_mut(&self.group_id(&pair.bitext)).ok_or_else(changed)?;
+            if pair.order == group.first && pair.order != group.kept_order {
+                let kept = moved.remove(&group.kept_order).ok_or_else(changed)?;
+                if self.clusters.is_some() {
+                    group.key = Some(self.side.readable_key(&kept, &self.normalization));
+                }
+                write_spilled(&mut writer, &SpilledPair { order: group.first, duplicate: false, bitext: kept })?;
+            }
+            if pair.order == group.kept_order {
+                if pair.order == group.first {
+                    if self.clusters.is_some() && group.count > 1 {
+                        group.key = Some(self.side.readable_key(&pair.bitext, &self.normalization));
                     }
+                    write_spilled(&mut writer, &pair)?;
                 }
-            }).collect();
-        bitext
+                continue;
+            }
+            if group.examples.len() < examples {
+                group.examples.push(DuplicateExample::new(&pair.bitext));
+            }
+            pair.duplicate = true;
+            write_spilled(&mut writer, &pair)?;
+        }
+        writer.flush()?;
+        if let Some(clusters) = &self.clusters {
+            for group in groups.into_values().filter(|x| x.count > 1) {
+                let key = group.key.unwrap_or_default();
+                clusters.add(DuplicateCluster { key, count: group.count, examples: group.examples });
+            }
+        }
+        fs::remove_file(path)
+    }
+
+    /// Returns the next batch of kept and removed pairs in input order, or
+    /// None once all pairs were returned. The first call deduplicates the
+    /// partitions. After an error, every later call returns it again.
+    pub fn finish(&self) -> io::Result<Option<(Vec<BiText>, Vec<Rejection>)>> {
+        let mut state = self.state.lock().unwrap();
+        state.check_failed()?;
+        let result = self.finish_locked(&mut state);
+        state.fail_on(result)
+    }
+
+    fn finish_locked(&self, state: &mut SpillState) -> io::Result<Option<(Vec<BiText>, Vec<Rejection>)>> {
+        if let SpillState::Spilling { writers, .. } = state {
+            for writer in writers.iter_mut() {
+                writer.flush()?;
+            }
+            let partitions: Vec<usize> = (0..self.partitions).collect();
+            for batch in partitions.chunks(self.parallel_partitions) {
+                batch.par_iter().try_for_each(|x| self.deduplicate_partition(*x))?;
+            }
+            let kept_paths: Vec<PathBuf> = partitions.iter().map(|x| self.partition_file(*x, true)).collect();
+            *state = SpillState::Merging(SpilledMerge::open(&kept_paths)?);
+        }
+        let SpillState::Merging(merge) = state else { return Ok(None) };
+        if merge.heads.is_empty() {
+            *state = SpillState::Empty;
+            (0..self.partitions).try_for_each(|x| fs::remove_file(self.partition_file(x, true)))?;
+            return Ok(None);
+        }
+        let mut kept = Vec::new();
+        let mut rejected = Vec::new();
+        while kept.len() + rejected.len() < MERGE_BATCH_SIZE {
+            let Some(pair) = merge.next()? else { break };
+            match pair.duplicate {
+                true => rejected.push(self.rejection(pair.bitext)),
+                false => kept.push(pair.bitext),
+            }
+        }
+        Ok(Some((kept, rejected)))
+    }
+}
+
+impl Drop for ExternalDeduplicator {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn read_spilled(path: &Path) -> io::Result<impl Iterator<Item = io::Result<SpilledPair>>> {
+    let lines = BufReader::new(File::open(path)?).lines();
+    Ok(lines.map(|line| Ok(serde_json::from_str(&line?)?)))
+}
+
+fn write_spilled(writer: &mut BufWriter<File>, pair: &SpilledPair) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, pair)?;
+    writer.write_all(b"\n")
+}
+
+fn next_spilled(lines: &mut Lines<BufReader<File>>) -> io::Result<Option<SpilledPair>> {
+    match lines.next() {
+        Some(line) => Ok(Some(serde_json::from_str(&line?)?)),
+        None => Ok(None),
     }
 }
 
@@ -100,20 +896,235 @@ mod tests{
         let bitexts = bitexts.into_iter().map(
             |x| BiText::new(String::from(x), None, Some(String::from(x)), None)
         ).collect();
-        let deduplicated = TargetDeduplicator::deduplicate(bitexts, false);
+        let deduplicated = ParallelDeduplicator::new(KeySide::Target, Normalization::default()).deduplicate_chunk(bitexts).0;
         assert_eq!(deduplicated.len(), 4);
     }
 
+    #[test]
+    pub fn test_deduplicator_chunks(){
+        let deduplicator = ParallelDeduplicator::new(KeySide::Target, Normalization::default());
+        let first = vec!["unique", "non-unique"].into_iter().map(
+            |x| BiText::new(String::from(x), None, Some(String::from(x)), None)
+        ).collect();
+        let second = vec!["non-unique", "1"].into_iter().map(
+            |x| BiText::new(String::from(x), None, Some(String::from(x)), None)
+        ).collect();
+        assert_eq!(deduplicator.deduplicate_chunk(first).0.len(), 2);
+        assert_eq!(deduplicator.deduplicate_chunk(second).0.len(), 1);
+    }
+
+    #[test]
+    pub fn test_deduplicator_normalization(){
+        let bitexts: Vec<BiText> = vec!["Page 1 of 10", "page 2 of 10", "Page 3 of 10.", "Seite 1 von 10"].into_iter().map(
+            |x| BiText::new(String::from("Page"), None, Some(String::from(x)), None)
+        ).collect();
+        let normalization = Normalization { lowercase: true, punctuation: true, digits: true, ..Normalization::default() };
+        let (deduplicated, _) = ParallelDeduplicator::new(KeySide::Target, normalization).deduplicate_chunk(bitexts.clone());
+        assert_eq!(deduplicated.len(), 2);
+        assert_eq!(deduplicated[0].translation.as_deref(), Some("Page 1 of 10"));
+        let normalization = Normalization { lowercase: true, ..Normalization::default() };
+        assert_eq!(ParallelDeduplicator::new(KeySide::Target, normalization).deduplicate_chunk(bitexts).0.len(), 4);
+    }
+
+    #[test]
+    pub fn test_deduplicator_sides(){
+        let bitexts: Vec<BiText> = vec![("ab", "c"), ("a", "bc"), ("ab", "d"), ("x", "c")].into_iter().map(
+            |(src, trg)| BiText::new(String::from(src), None, Some(String::from(trg)), None)
+        ).collect();
+        let count = |side| ParallelDeduplicator::new(side, Normalization::default()).deduplicate_chunk(bitexts.clone()).0.len();
+        assert_eq!(count(KeySide::Source), 3);
+        assert_eq!(count(KeySide::Target), 3);
+        assert_eq!(count(KeySide::Pair), 4);
+    }
+
+    #[test]
+    pub fn test_deduplicator_keep_policy(){
+        let mut bitexts: Vec<BiText> = vec![("a", "x"), ("b", "y"), ("a", "long x"), ("a", "xx")].into_iter().map(
+            |(src, trg)| BiText::new(String::from(src), None, Some(String::from(trg)), None)
+        ).collect();
+        bitexts[0].scores.insert(String::from("langid_filter"), 0.5);
+        bitexts[3].scores.insert(String::from("langid_filter"), 0.9);
+        let kept = |keep: KeepPolicy| {
+            let (kept, rejected) = deduplicate_external(&bitexts, keep, u64::MAX);
+            assert_eq!(rejected.len(), 2);
+            kept.into_iter().map(|x| x.translation.unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(kept(KeepPolicy::First), vec!["x", "y"]);
+        assert_eq!(kept(KeepPolicy::Last), vec!["xx", "y"]);
+        assert_eq!(kept(KeepPolicy::Longest), vec!["long x", "y"]);
+        assert_eq!(kept(KeepPolicy::HighestScore(String::from("langid_filter"))), vec!["xx", "y"]);
+    }
+
+    #[test]
+    pub fn test_deduplicator_clusters(){
+        let bitexts: Vec<BiText> = vec![("Home", "x"), ("a", "y"), ("HOME", "z"), ("b", "w"), ("home", "v"), ("a", "u")]
+            .into_iter()
+            .enumerate()
+            .map(|(line, (src, trg))| BiText {
+                line: Some(line as u64 + 1),
+                ..BiText::new(String::from(src), None, Some(String::from(trg)), None)
+            })
+            .collect();
+        let normalization = Normalization { lowercase: true, ..Normalization::default() };
+        let deduplicator = ParallelDeduplicator::new(KeySide::Source, normalization).with_cluster_report(1, 1);
+        deduplicator.deduplicate_chunk(bitexts[..3].to_vec());
+        deduplicator.deduplicate_chunk(bitexts[3..].to_vec());
+        let example = DuplicateExample { line: Some(3), source: String::from("HOME"), target: Some(String::from("z")) };
+        assert_eq!(
+            deduplicator.largest_clusters(),
+            vec![DuplicateCluster { key: String::from("home"), count: 3, examples: vec![example] }]
+        );
+
+        let deduplicator = ExternalDeduplicator::new(KeySide::Source, normalization, &std::env::temp_dir())
+            .unwrap()
+            .with_keep(KeepPolicy::Last)
+            .with_cluster_report(5, 2);
+        deduplicator.spill_chunk(bitexts).unwrap();
+        while deduplicator.finish().unwrap().is_some() {}
+        let clusters = deduplicator.largest_clusters();
+        assert_eq!(clusters.iter().map(|x| (x.key.as_str(), x.count)).collect::<Vec<_>>(), vec![("home", 3), ("a", 2)]);
+        assert_eq!(clusters[0].examples.iter().map(|x| x.line).collect::<Vec<_>>(), vec![Some(1), Some(3)]);
+    }
+
+    #[test]
+    pub fn test_deduplicator_clusters_bounded(){
+        let bitexts: Vec<BiText> = (0..10_000).map(|x| {
+            let text = match x % 10 {
+                0 => String::from("heavy"),
+                _ => format!("{}", x % 4000),
+            };
+            BiText::new(text, None, None, None)
+        }).collect();
+        let deduplicator = ParallelDeduplicator::new(KeySide::Source, Normalization::default()).with_cluster_report(1, 0);
+        deduplicator.deduplicate_chunk(bitexts);
+        let clusters = deduplicator.largest_clusters();
+        assert_eq!(clusters[0].key, "heavy");
+        assert!(clusters[0].count >= 1000);
+        assert!(deduplicator.clusters.as_ref().unwrap().tracked.lock().unwrap().groups.len() <= MIN_TRACKED_GROUPS);
+    }
+
     #[test]
     pub fn test_deduplicator_parallel(){
         let bitexts = vec!["unique", "non-unique", "non-unique", "1", "2","non-unique"];
         let bitexts = bitexts.into_iter().map(
             |x| BiText::new(String::from(x), None, Some(String::from(x)), None)
         ).collect();
-        let deduplicated = TargetDeduplicatorParallel::deduplicate(bitexts, false);
+        let deduplicated = ParallelDeduplicator::new(KeySide::Source, Normalization::default()).deduplicate_chunk(bitexts).0;
         assert_eq!(deduplicated.len(), 4);
     }
-}
 
+    #[test]
+    pub fn test_deduplicator_parallel_order(){
+        let deduplicator = ParallelDeduplicator::new(KeySide::Source, Normalization { lowercase: true, ..Normalization::default() });
+        let bitexts: Vec<BiText> = (0..10_000).map(
+            |x| BiText::new(format!("{}", x % 1000), None, Some(format!("{}", x)), None)
+        ).collect();
+        let (deduplicated, rejected) = deduplicator.deduplicate_chunk(bitexts.clone());
+        assert_eq!(deduplicated, bitexts[..1000].to_vec());
+        assert_eq!(rejected[0].bitext, bitexts[1000]);
+        assert_eq!(deduplicator.deduplicate_chunk(bitexts).0.len(), 0);
+
+        let cased = vec!["Hello", "hello"].into_iter().map(
+            |x| BiText::new(String::from(x), None, None, None)
+        ).collect();
+        assert_eq!(deduplicator.deduplicate_chunk(cased).0.len(), 1);
+    }
 
+    #[test]
+    pub fn test_deduplicator_key_stores(){
+        let bitexts: Vec<BiText> = (0..10_000).map(
+            |x| BiText::new(format!("{}", x % 1000), None, None, None)
+        ).collect();
+        let stores = [
+            KeyStore::Exact,
+            KeyStore::Hash64,
+            KeyStore::Hash128,
+            KeyStore::Bloom { expected_keys: 100_000, false_positive_rate: 0.0001 },
+        ];
+        for store in stores {
+            let deduplicator = ParallelDeduplicator::new(KeySide::Source, Normalization::default()).with_store(store);
+            assert_eq!(deduplicator.deduplicate_chunk(bitexts.clone()).0.len(), 1000);
+            assert_eq!(deduplicator.seen_keys(), 1000);
+            assert!(deduplicator.false_positive_rate() < 0.0001);
+        }
+        let deduplicator = ParallelDeduplicator::new(KeySide::Source, Normalization::default()).with_store(KeyStore::Hash64);
+        deduplicator.deduplicate_chunk(bitexts);
+        assert!(deduplicator.false_positive_rate() > 0.0);
+    }
 
+    /// Runs `bitexts` through an [`ExternalDeduplicator`] on the source in
+    /// chunks of 4000 pairs.
+    fn deduplicate_external(bitexts: &[BiText], keep: KeepPolicy, max_partition_size: u64) -> (Vec<BiText>, Vec<Rejection>) {
+        let external = ExternalDeduplicator::new(KeySide::Source, Normalization::default(), &std::env::temp_dir())
+            .unwrap()
+            .with_keep(keep)
+            .with_partitions(7)
+            .with_max_partition_size(max_partition_size)
+            .with_parallel_partitions(2);
+        for chunk in bitexts.chunks(4000) {
+            external.spill_chunk(chunk.to_vec()).unwrap();
+        }
+        let mut kept = Vec::new();
+        let mut rejected = Vec::new();
+        while let Some((batch, rejects)) = external.finish().unwrap() {
+            assert!(batch.len() + rejects.len() <= MERGE_BATCH_SIZE);
+            kept.extend(batch);
+            rejected.extend(rejects);
+        }
+        (kept, rejected)
+    }
+
+    #[test]
+    pub fn test_deduplicator_external(){
+        let bitexts: Vec<BiText> = (0..25_000).map(|x| BiText {
+            line: Some(x as u64 + 1),
+            ..BiText::new(format!("{}", x % 7000), None, Some("a".repeat(x % 3)), None)
+        }).collect();
+        for keep in [KeepPolicy::First, KeepPolicy::Last, KeepPolicy::Longest] {
+            let mut expected: Vec<BiText> = Vec::new();
+            let mut groups = HashMap::new();
+            for bitext in bitexts.iter() {
+                match groups.get(&bitext.text) {
+                    Some(&index) if keep.prefers(keep.rank(bitext), keep.rank(&expected[index])) => expected[index] = bitext.clone(),
+                    Some(_) => {}
+                    None => {
+                        groups.insert(bitext.text.clone(), expected.len());
+                        expected.push(bitext.clone());
+                    }
+                }
+            }
+            for max_partition_size in [u64::MAX, 20_000] {
+                let (kept, rejected) = deduplicate_external(&bitexts, keep.clone(), max_partition_size);
+                assert_eq!(kept, expected);
+                assert_eq!(rejected.len(), 18_000);
+            }
+        }
+
+        let external = ExternalDeduplicator::new(KeySide::Source, Normalization::default(), &std::env::temp_dir())
+            .unwrap()
+            .with_partitions(7)
+            .with_cluster_report(3, 2);
+        let dir = external.dir.clone();
+        external.spill_chunk(bitexts).unwrap();
+        while external.finish().unwrap().is_some() {}
+        let clusters = external.largest_clusters();
+        assert_eq!(clusters.iter().map(|x| (x.key.as_str(), x.count)).collect::<Vec<_>>(), vec![("0", 4), ("1", 4), ("10", 4)]);
+        assert_eq!(clusters[0].examples.iter().map(|x| x.line).collect::<Vec<_>>(), vec![Some(7001), Some(14001)]);
+        drop(external);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    pub fn test_deduplicator_external_failure(){
+        let external = ExternalDeduplicator::new(KeySide::Source, Normalization::default(), &std::env::temp_dir())
+            .unwrap()
+            .with_partitions(2);
+        let bitexts = (0..100).map(|x| BiText::new(format!("{}", x % 10), None, None, None)).collect();
+        external.spill_chunk(bitexts).unwrap();
+        fs::remove_file(external.partition_file(1, false)).unwrap();
+        let error = external.finish().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(external.finish().err().unwrap().kind(), io::ErrorKind::NotFound);
+        assert!(external.spill_chunk(Vec::new()).is_err());
+    }
+}
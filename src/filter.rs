@@ -1,25 +1,187 @@
 use std::any::Any;
 use crate::model::BiText;
-use levenshtein;
+use crate::normalize::Normalization;
+use serde::Deserialize;
 use rayon::prelude::*;
 use regex;
 use regex::Regex;
 use std::cmp::{max, min};
-use std::collections::{HashSet, LinkedList};
+use std::collections::{BTreeMap, HashSet, LinkedList};
 use std::ops::Deref;
 use std::path::Prefix::Verbatim;
 use std::str::FromStr;
 use unicode_segmentation::UnicodeSegmentation;
 
-use lingua::{Language, LanguageDetector, LanguageDetectorBuilder};
+use std::sync::Arc;
+use lingua::Language;
+use crate::langid::{parse_language, DetectorOptions, SharedDetector};
 
-pub trait Filter {
-    fn filter_text(self, texts: Vec<BiText>) -> Vec<BiText>;
+/// A pair that was removed by a filter, with the reason it was removed.
+#[derive(PartialEq, Debug, Clone)]
+pub struct Rejection {
+    pub bitext: BiText,
+    pub filter: &'static str,
+    pub reason: String,
+}
+
+/// The number a filter bases its decision on, see [`Filter::score`].
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Score {
+    Pair(f64),
+    /// For filters that check every side against its own thresholds. The
+    /// sides that are not inspected have no score.
+    Sides { source: Option<f64>, target: Option<f64> },
+}
+
+pub trait Filter: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Returns why the pair should be removed, or None if it is kept.
+    fn reject_reason(&self, _bitext: &BiText) -> Option<String> {
+        None
+    }
+
+    /// Whether the filter changes the text of the pairs it keeps.
+    fn modifies(&self) -> bool {
+        false
+    }
+
+    /// The number the filter bases its decision on, so that thresholds can be
+    /// tuned on a scored corpus. Filters without such a number score 1 for
+    /// pairs they keep and 0 for the others.
+    fn score(&self, bitext: &BiText) -> Option<Score> {
+        Some(Score::Pair(if self.reject_reason(bitext).is_none() { 1.0 } else { 0.0 }))
+    }
+
+    /// Whether the scores are enough to reproduce the decisions of the filter.
+    fn can_score(&self) -> bool {
+        !self.modifies()
+    }
+
+    /// Filter specific numbers for the statistics report.
+    fn details(&self) -> BTreeMap<String, f64> {
+        BTreeMap::new()
+    }
+
+    fn filter_text(&self, texts: Vec<BiText>) -> Vec<BiText> {
+        texts
+            .into_par_iter()
+            .filter(|x| self.reject_reason(x).is_none())
+            .collect()
+    }
+
+    /// Like [`Filter::filter_text`], but also returns the removed pairs.
+    fn filter_with_rejects(&self, texts: Vec<BiText>) -> (Vec<BiText>, Vec<Rejection>) {
+        partition_rejects(self, texts)
+    }
+}
+
+/// Splits `texts` into the pairs `filter` keeps and the ones it rejects.
+pub fn partition_rejects<F: Filter + ?Sized>(filter: &F, texts: Vec<BiText>) -> (Vec<BiText>, Vec<Rejection>) {
+    let checked: Vec<(BiText, Option<String>)> = texts
+        .into_par_iter()
+        .map(|x| {
+            let reason = filter.reject_reason(&x);
+            (x, reason)
+        })
+        .collect();
+    let mut kept = Vec::with_capacity(checked.len());
+    let mut rejected = Vec::new();
+    for (bitext, reason) in checked {
+        match reason {
+            Some(reason) => rejected.push(Rejection { bitext, filter: filter.name(), reason }),
+            None => kept.push(bitext),
+        }
+    }
+    (kept, rejected)
+}
+
+/// Which side of a pair a filter inspects.
+#[derive(PartialEq, Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Source,
+    Target,
+    /// Both sides have to pass.
+    #[default]
+    Both,
+    /// At least one side has to pass.
+    Either,
+}
+
+impl Side {
+    /// Combines the results of `check` for the sides that are inspected.
+    ///
+    /// `check` gets the text of a side and whether it is the target. Pairs
+    /// without a translation are rejected by [`Side::Target`], which has
+    /// nothing to check, and only checked on the source side otherwise.
+    pub fn reject_reason<F>(self, bitext: &BiText, check: F) -> Option<String>
+    where
+        F: Fn(&str, bool) -> Option<String>,
+    {
+        let source = || check(&bitext.text, false).map(|x| format!("source {}", x));
+        let target = || {
+            let translation = bitext.translation.as_deref()?;
+            check(translation, true).map(|x| format!("target {}", x))
+        };
+        match self {
+            Side::Source => source(),
+            Side::Target if bitext.translation.is_none() => Some(String::from("target is missing")),
+            Side::Target => target(),
+            Side::Both => source().or_else(target),
+            Side::Either if bitext.translation.is_none() => source(),
+            Side::Either => match (source(), target()) {
+                (Some(source), Some(target)) => Some(format!("{}, {}", source, target)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Combines the scores `score` gives the sides that are inspected: the
+    /// worse one counts for [`Side::Both`] and the better one for [`Side::Either`].
+    pub fn score<F>(self, bitext: &BiText, higher_is_better: bool, score: F) -> Option<f64>
+    where
+        F: Fn(&str, bool) -> Option<f64>,
+    {
+        let source = score(&bitext.text, false);
+        let target = bitext.translation.as_deref().and_then(|x| score(x, true));
+        let (worse, better): (fn(f64, f64) -> f64, fn(f64, f64) -> f64) = match higher_is_better {
+            true => (f64::min, f64::max),
+            false => (f64::max, f64::min),
+        };
+        let combine = |choose: fn(f64, f64) -> f64| match (source, target) {
+            (Some(source), Some(target)) => Some(choose(source, target)),
+            (source, target) => source.or(target),
+        };
+        match self {
+            Side::Source => source,
+            Side::Target => target,
+            Side::Both => combine(worse),
+            Side::Either => combine(better),
+        }
+    }
+
+    /// The score of every inspected side on its own. [`Side::Either`] can not
+    /// be told apart from [`Side::Both`] this way, so filters scoring their
+    /// sides separately can not run in score mode with it.
+    pub fn side_scores<F>(self, bitext: &BiText, score: F) -> Score
+    where
+        F: Fn(&str, bool) -> f64,
+    {
+        let target = bitext.translation.as_deref().map(|x| score(x, true));
+        match self {
+            Side::Source => Score::Sides { source: Some(score(&bitext.text, false)), target: None },
+            Side::Target => Score::Sides { source: None, target },
+            Side::Both | Side::Either => Score::Sides { source: Some(score(&bitext.text, false)), target },
+        }
+    }
 }
 
 // LengthFilter
-#[derive(PartialEq)]
+#[derive(PartialEq, Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum LengthFilterUnit {
+    #[default]
     Char,
     Word,
 }
@@ -27,46 +189,78 @@ pub enum LengthFilterUnit {
 pub struct LengthFilter {
     min_length: i16,
     max_length: i16,
+    trg_min_length: i16,
+    trg_max_length: i16,
     unit: LengthFilterUnit,
+    side: Side,
 }
 
 impl LengthFilter {
+    /// Checks both sides with the same limits.
     pub(crate) fn new(min_length: i16, max_length: i16, unit: LengthFilterUnit) -> LengthFilter {
         LengthFilter {
             min_length,
             max_length,
+            trg_min_length: min_length,
+            trg_max_length: max_length,
             unit,
+            side: Side::default(),
+        }
+    }
+
+    pub fn with_side(mut self, side: Side) -> Self {
+        self.side = side;
+        self
+    }
+
+    /// Uses different limits for the target side.
+    pub fn with_target_length(mut self, min_length: i16, max_length: i16) -> Self {
+        self.trg_min_length = min_length;
+        self.trg_max_length = max_length;
+        self
+    }
+
+    fn get_length(&self, text: &str) -> usize {
+        match self.unit {
+            LengthFilterUnit::Char => text.graphemes(true).count(),
+            LengthFilterUnit::Word => text.split(" ").count(),
         }
     }
 }
 
 impl Filter for LengthFilter {
-    fn filter_text(self, texts: Vec<BiText>) -> Vec<BiText> {
-        let filtered;
-        match self.unit {
-            LengthFilterUnit::Char => {
-                filtered = texts
-                    .into_par_iter()
-                    .filter(|x| {
-                        let length = x.text.graphemes(true).count();
-                         length >= self.min_length as usize
-                            && length <= self.max_length as usize
-                    })
-                    .collect::<Vec<BiText>>();
-            }
-            LengthFilterUnit::Word => {
-                filtered = texts
-                    .into_par_iter()
-                    .filter(|x| {
-                        let length = x.text.split(" ").count();
-                        length >= self.min_length as usize
-                            && length <= self.max_length as usize
-                    })
-                    .collect::<Vec<BiText>>();
+    fn name(&self) -> &'static str {
+        "length_filter"
+    }
+
+    fn reject_reason(&self, bitext: &BiText) -> Option<String> {
+        self.side.reject_reason(bitext, |text, is_target| {
+            let (min_length, max_length) = match is_target {
+                true => (self.trg_min_length, self.trg_max_length),
+                false => (self.min_length, self.max_length),
+            };
+            let length = self.get_length(text);
+            let unit = match self.unit {
+                LengthFilterUnit::Char => "chars",
+                LengthFilterUnit::Word => "words",
+            };
+            if length < min_length as usize {
+                Some(format!("length {} {} < {}", length, unit, min_length))
+            } else if length > max_length as usize {
+                Some(format!("length {} {} > {}", length, unit, max_length))
+            } else {
+                None
             }
-        }
+        })
+    }
 
-        filtered
+    /// The length of every inspected side.
+    fn score(&self, bitext: &BiText) -> Option<Score> {
+        Some(self.side.side_scores(bitext, |text, _| self.get_length(text) as f64))
+    }
+
+    fn can_score(&self) -> bool {
+        self.side != Side::Either
     }
 }
 
@@ -80,155 +274,375 @@ impl LengthRatioFilter {
         Self { threshold, unit }
     }
 
-    fn get_length(&mut self, segment: &String) -> usize {
+    fn get_length(&self, segment: &String) -> usize {
         if self.unit == LengthFilterUnit::Word {
             return segment.split(" ").count();
         }
         segment.len()
     }
 
-    fn accept(&mut self, bitext: &BiText) -> bool {
+}
+
+impl Filter for LengthRatioFilter {
+    fn name(&self) -> &'static str {
+        "length_ratio_filter"
+    }
+
+    fn reject_reason(&self, bitext: &BiText) -> Option<String> {
         let src_len = self.get_length(&bitext.text);
         let trg_len = match &bitext.translation {
             Some(x) => self.get_length(x),
             None => 0usize,
         };
-        return if src_len == 0 && trg_len == 0 {
-            false
-        } else if src_len == 0 || trg_len == 0 {
-            false
+        if src_len == 0 || trg_len == 0 {
+            return Some(format!("empty segment ({} / {})", src_len, trg_len));
+        }
+        let ratio = min(src_len, trg_len) as f32 / max(src_len, trg_len) as f32;
+        if ratio < self.threshold {
+            Some(format!("ratio {:.2} < {}", ratio, self.threshold))
         } else {
-            min(src_len, trg_len) as f32 / max(src_len, trg_len) as f32 >= self.threshold
-        };
+            None
+        }
     }
-}
 
-impl Filter for LengthRatioFilter {
-    fn filter_text(mut self, texts: Vec<BiText>) -> Vec<BiText> {
-        texts
-            .into_iter()
-            .filter(|x| self.accept(x))
-            .collect::<Vec<BiText>>()
+    /// The ratio of the shorter to the longer side, 0 if a side is empty or missing.
+    fn score(&self, bitext: &BiText) -> Option<Score> {
+        let src_len = self.get_length(&bitext.text);
+        let trg_len = bitext.translation.as_ref().map(|x| self.get_length(x)).unwrap_or(0);
+        if src_len == 0 || trg_len == 0 {
+            return Some(Score::Pair(0.0));
+        }
+        Some(Score::Pair(min(src_len, trg_len) as f64 / max(src_len, trg_len) as f64))
     }
 }
 
 // LongWord Filter
 pub struct LongWordFilter {
     threshold: i16,
+    trg_threshold: i16,
+    side: Side,
 }
 
 impl LongWordFilter {
+    /// Checks both sides with the same threshold.
     pub fn new(threshold: i16) -> Self {
-        Self { threshold }
+        Self { threshold, trg_threshold: threshold, side: Side::default() }
+    }
+
+    pub fn with_side(mut self, side: Side) -> Self {
+        self.side = side;
+        self
+    }
+
+    /// Uses a different threshold for the target side.
+    pub fn with_target_threshold(mut self, threshold: i16) -> Self {
+        self.trg_threshold = threshold;
+        self
     }
 }
 
 impl Filter for LongWordFilter {
-    fn filter_text(self, texts: Vec<BiText>) -> Vec<BiText> {
-        texts
-            .into_par_iter()
-            .filter(|x| {
-                let mut keep = true;
-                for word in x.text.split(" ") {
-                    if word.graphemes(true).count() > self.threshold as usize {
-                        keep = false;
-                        break;
-                    }
-                }
-                keep
-            })
-            .collect()
+    fn name(&self) -> &'static str {
+        "long_word_filter"
+    }
+
+    fn reject_reason(&self, bitext: &BiText) -> Option<String> {
+        self.side.reject_reason(bitext, |text, is_target| {
+            let threshold = if is_target { self.trg_threshold } else { self.threshold };
+            text.split(" ")
+                .map(|word| word.graphemes(true).count())
+                .find(|length| *length > threshold as usize)
+                .map(|length| format!("word length {} > {}", length, threshold))
+        })
+    }
+
+    /// The length of the longest word of every inspected side.
+    fn score(&self, bitext: &BiText) -> Option<Score> {
+        Some(self.side.side_scores(bitext, |text, _| {
+            text.split(" ").map(|word| word.graphemes(true).count()).max().unwrap_or(0) as f64
+        }))
+    }
+
+    fn can_score(&self) -> bool {
+        self.side != Side::Either
     }
 }
 
 pub struct RegExpFilter {
     regexp: Regex,
+    trg_regexp: Regex,
     accept: bool,
+    side: Side,
 }
 
 impl RegExpFilter {
+    /// Checks both sides with the same expression.
     pub fn new(regexp: &str, accept: bool) -> RegExpFilter {
+        let regexp = Regex::new(regexp).unwrap();
         RegExpFilter {
-            regexp: Regex::new(regexp).unwrap(),
+            trg_regexp: regexp.clone(),
+            regexp,
             accept,
+            side: Side::default(),
         }
     }
+
+    pub fn with_side(mut self, side: Side) -> Self {
+        self.side = side;
+        self
+    }
+
+    /// Uses a different expression for the target side.
+    pub fn with_target_regexp(mut self, regexp: &str) -> Self {
+        self.trg_regexp = Regex::new(regexp).unwrap();
+        self
+    }
 }
 
 impl Filter for RegExpFilter {
-    fn filter_text(self, texts: Vec<BiText>) -> Vec<BiText> {
-        texts
-            .into_par_iter()
-            .filter(|x| {
-                let is_match = self.regexp.is_match(x.text.as_str());
-                is_match && self.accept || !is_match && !self.accept
-            })
-            .collect()
+    fn name(&self) -> &'static str {
+        "regexp_filter"
+    }
+
+    fn reject_reason(&self, bitext: &BiText) -> Option<String> {
+        self.side.reject_reason(bitext, |text, is_target| {
+            let regexp = if is_target { &self.trg_regexp } else { &self.regexp };
+            match (regexp.is_match(text), self.accept) {
+                (true, false) => Some(format!("matches '{}'", regexp)),
+                (false, true) => Some(format!("does not match '{}'", regexp)),
+                _ => None,
+            }
+        })
     }
 }
 
+/// Checks that the source is in [`BiText::language`] and the target in
+/// [`BiText::translation_language`].
+///
+/// The configured languages are used for pairs without languages, a side
+/// without any expected language is not checked.
 pub struct LangIdFilter {
-    lang: Language,
-    model: LanguageDetector,
+    src_lang: Option<Language>,
+    trg_lang: Option<Language>,
+    min_confidence: f64,
+    short_length: usize,
+    options: DetectorOptions,
+    model: Arc<SharedDetector>,
 }
 
 impl LangIdFilter {
-    pub fn new(lang: String) -> Self {
-        let mut model = LanguageDetectorBuilder::from_all_languages();
-        let model = model.build();
-
+    pub fn new(src_lang: Option<Language>, trg_lang: Option<Language>, options: DetectorOptions) -> Self {
         LangIdFilter {
-            lang: Language::from_str(&lang).expect("Invalid Language"),
-            model,
+            src_lang,
+            trg_lang,
+            min_confidence: 0.5,
+            short_length: 0,
+            model: options.detector(),
+            options,
+        }
+    }
+
+    /// The confidence the detector must have in the expected language.
+    pub fn with_min_confidence(mut self, min_confidence: f64) -> Self {
+        self.min_confidence = min_confidence;
+        self
+    }
+
+    /// Segments shorter than `short_length` characters also pass if no
+    /// language is detected with enough confidence, as detection is
+    /// unreliable for them. They are still removed if another language is.
+    pub fn with_short_length(mut self, short_length: usize) -> Self {
+        self.short_length = short_length;
+        self
+    }
+
+    /// The confidence in `lang`, or 1 for short segments that pass because no
+    /// language is detected with `min_confidence`.
+    fn confidence(&self, text: &str, lang: Language) -> f64 {
+        if !self.options.is_candidate(lang) {
+            return 0.0;
+        }
+        let confidence = self.model.compute_language_confidence(text, lang);
+        if confidence >= self.min_confidence || text.chars().count() >= self.short_length {
+            return confidence;
+        }
+        match self.detected(text) {
+            None => 1.0,
+            Some(_) => confidence,
+        }
+    }
+
+    /// The most likely language, if it is detected with `min_confidence`.
+    fn detected(&self, text: &str) -> Option<Language> {
+        self.model
+            .compute_language_confidence_values(text)
+            .into_iter()
+            .next()
+            .filter(|(_, confidence)| *confidence >= self.min_confidence)
+            .map(|(detected, _)| detected)
+    }
+
+    fn check(&self, text: &str, lang: Language) -> Option<String> {
+        if !self.options.is_candidate(lang) {
+            return Some(format!("expected {}, which is not a candidate language", lang));
+        }
+        let confidence = self.model.compute_language_confidence(text, lang);
+        if confidence >= self.min_confidence {
+            return None;
+        }
+        match self.detected(text) {
+            None if text.chars().count() < self.short_length => None,
+            Some(detected) if detected != lang => Some(format!("detected {}, expected {}", detected, lang)),
+            _ => Some(format!("confidence {:.2} for {} < {}", confidence, lang, self.min_confidence)),
         }
     }
 }
 
 impl Filter for LangIdFilter {
-    fn filter_text(self, texts: Vec<BiText>) -> Vec<BiText> {
-        texts
-            .into_par_iter()
-            .filter(|x| match self.model.detect_language_of(x.text.as_str()) {
-                Some(val) => {
-                    val.eq(&self.lang)
-                }
-                None => false,
+    fn name(&self) -> &'static str {
+        "langid_filter"
+    }
+
+    fn reject_reason(&self, bitext: &BiText) -> Option<String> {
+        let src_lang = bitext.language.as_deref().and_then(parse_language).or(self.src_lang);
+        let trg_lang = bitext.translation_language.as_deref().and_then(parse_language).or(self.trg_lang);
+        Side::Both.reject_reason(bitext, |text, is_target| {
+            let lang = if is_target { trg_lang } else { src_lang };
+            self.check(text, lang?)
+        })
+    }
+
+    /// The lowest confidence in the expected language of any side. Short
+    /// segments that pass because of `short_length` score 1.
+    fn score(&self, bitext: &BiText) -> Option<Score> {
+        let src_lang = bitext.language.as_deref().and_then(parse_language).or(self.src_lang);
+        let trg_lang = bitext.translation_language.as_deref().and_then(parse_language).or(self.trg_lang);
+        Side::Both
+            .score(bitext, true, |text, is_target| {
+                let lang = if is_target { trg_lang } else { src_lang }?;
+                Some(self.confidence(text, lang))
             })
-            .collect()
+            .map(Score::Pair)
     }
 }
 
+/// Removes pairs whose target is a copy or near copy of the source, which
+/// usually means the segment was not translated.
+///
+/// Similarity is one minus the edit distance over graphemes or words, divided
+/// by the length of the longer side, so it is between 0 and 1 regardless of
+/// the segment length.
 pub struct SimilarityFilter {
-    threshold: i16,
-    lowercase: bool,
+    threshold: f32,
+    unit: LengthFilterUnit,
+    normalization: Normalization,
 }
 
 impl SimilarityFilter {
-    pub fn new(threshold: i16, lowercase: bool) -> SimilarityFilter {
+    pub fn new(threshold: f32, unit: LengthFilterUnit) -> SimilarityFilter {
         SimilarityFilter {
             threshold,
-            lowercase,
+            unit,
+            normalization: Normalization::default(),
         }
     }
+
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    fn similarity(&self, text: &str, translation: &str) -> f64 {
+        let text = self.normalization.apply(text);
+        let translation = self.normalization.apply(translation);
+        let tokens = |text: &str| -> Vec<String> {
+            match self.unit {
+                LengthFilterUnit::Char => text.graphemes(true).map(String::from).collect(),
+                LengthFilterUnit::Word => text
+                    .split_word_bounds()
+                    .filter(|x| !x.trim().is_empty())
+                    .map(String::from)
+                    .collect(),
+            }
+        };
+        let (text, translation) = (tokens(&text), tokens(&translation));
+        let length = max(text.len(), translation.len());
+        if length == 0 {
+            return 1.0;
+        }
+        1.0 - edit_distance(&text, &translation) as f64 / length as f64
+    }
+}
+
+/// Levenshtein distance between two sequences.
+fn edit_distance<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, x) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(x != y);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
 }
+
 impl Filter for SimilarityFilter {
-    fn filter_text(self, texts: Vec<BiText>) -> Vec<BiText> {
-        texts
-            .into_par_iter()
-            .filter(|x| match &x.translation {
-                Some(translation) => {
-                    if !self.lowercase {
-                        ((x.text.len() as i16-translation.len() as i16).abs() as i16<=self.threshold)&&(levenshtein::levenshtein(&*x.text, &*translation) > self.threshold as usize)
-                    } else {
-                        levenshtein::levenshtein(
-                            &*x.text.to_lowercase(),
-                            &*translation.to_lowercase(),
-                        ) > self.threshold as usize
-                    }
-                }
-                None => true,
-            })
-            .collect()
+    fn name(&self) -> &'static str {
+        "similarity_filter"
+    }
+
+    fn reject_reason(&self, bitext: &BiText) -> Option<String> {
+        let similarity = self.similarity(&bitext.text, bitext.translation.as_ref()?);
+        if similarity >= self.threshold as f64 {
+            Some(format!("similarity {:.2} >= {}", similarity, self.threshold))
+        } else {
+            None
+        }
+    }
+
+    /// The similarity between source and target.
+    fn score(&self, bitext: &BiText) -> Option<Score> {
+        Some(Score::Pair(self.similarity(&bitext.text, bitext.translation.as_ref()?)))
+    }
+}
+
+/// Removes pairs by the scores earlier filters wrote in score mode, so that
+/// the thresholds can be changed without running these filters again.
+///
+/// Pairs without a score for a filter are not checked against its threshold.
+pub struct ThresholdFilter {
+    min: BTreeMap<String, f64>,
+    max: BTreeMap<String, f64>,
+}
+
+impl ThresholdFilter {
+    pub fn new(min: BTreeMap<String, f64>, max: BTreeMap<String, f64>) -> Self {
+        ThresholdFilter { min, max }
+    }
+}
+
+impl Filter for ThresholdFilter {
+    fn name(&self) -> &'static str {
+        "threshold_filter"
+    }
+
+    fn reject_reason(&self, bitext: &BiText) -> Option<String> {
+        for (name, min) in self.min.iter() {
+            match bitext.scores.get(name) {
+                Some(score) if score < min => return Some(format!("{} score {:.2} < {}", name, score, min)),
+                _ => {}
+            }
+        }
+        for (name, max) in self.max.iter() {
+            match bitext.scores.get(name) {
+                Some(score) if score > max => return Some(format!("{} score {:.2} > {}", name, score, max)),
+                _ => {}
+            }
+        }
+        None
     }
 }
 
@@ -347,20 +761,25 @@ impl HtmlFilter {
 }
 
 impl Filter for HtmlFilter{
-    fn filter_text(self, texts: Vec<BiText>) -> Vec<BiText> {
-        let re = Regex::from_str("(?s)</?(([A-Za-z0-9_-]+).*?)>").unwrap();
+    fn name(&self) -> &'static str {
+        "html_filter"
+    }
 
-        let s =  String::from("<li name='peter'>Abc</test>");
+    fn modifies(&self) -> bool {
+        true
+    }
 
-        //let captures1: Vec<String> = re.captures_iter(&*s).map(|x| x.iter().into_iter().flat_map(|x| x.unwrap().as_str().parse())).collect();
-        println!("{}", re.replace_all(&*s, ""));
+    fn score(&self, _bitext: &BiText) -> Option<Score> {
+        None
+    }
+
+    fn filter_with_rejects(&self, texts: Vec<BiText>) -> (Vec<BiText>, Vec<Rejection>) {
+        (self.filter_text(texts), Vec::new())
+    }
+
+    fn filter_text(&self, texts: Vec<BiText>) -> Vec<BiText> {
+        let re = Regex::from_str("(?s)</?(([A-Za-z0-9_-]+).*?)>").unwrap();
 
-        let replaced =  re.replace_all(&*s, |capture: &regex::Captures| {
-            if (self.html_list.contains(&*capture[2].to_lowercase())) {
-                return String::from("") //capture[1].to_uppercase()
-            }
-            capture[0].to_string()
-        });
         let filtered = texts.into_iter().map(
             |mut s| {
                 s.text = re.replace_all(&*s.text, |capture: &regex::Captures| {
@@ -382,8 +801,6 @@ impl Filter for HtmlFilter{
             }
         ).collect();
 
-        println!("{}", replaced.to_string());
-
         filtered
     }
 }
@@ -459,6 +876,21 @@ mod tests {
         assert_eq!(cleaned.len(), 2);
     }
 
+    #[test]
+    fn test_length_ratio_filter_rejects() {
+        let test_vectors = vec!["abcd", "a b", "a b c d e"]
+            .into_iter()
+            .map(|x| BiText::new(String::from(x), None, Some(String::from("a b c d e")), None))
+            .collect();
+        let cleaner = LengthRatioFilter::new(0.8, LengthFilterUnit::Word);
+        let (kept, rejected) = cleaner.filter_with_rejects(test_vectors);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(rejected.len(), 2);
+        assert_eq!(rejected[0].filter, "length_ratio_filter");
+        assert_eq!(rejected[0].reason, "ratio 0.20 < 0.8");
+        assert_eq!(rejected[1].bitext.text, "a b");
+    }
+
     #[test]
     fn test_long_word_filter_passing() {
         let test_vectors = vec!["All of this is okay."]
@@ -479,11 +911,80 @@ mod tests {
         .into_iter()
         .map(|x| BiText::new(String::from(x), None, None, None))
         .collect();
-        let cleaner = LongWordFilter { threshold: 10 };
+        let cleaner = LongWordFilter::new(10);
         let cleaned = cleaner.filter_text(test_vectors);
         assert_eq!(cleaned.len(), 0)
     }
 
+    #[test]
+    fn test_length_filter_sides() {
+        let test_vectors = vec![
+            ("a b", "a b c d e f"),
+            ("a b c d", "a b c d e f g h i j"),
+            ("a b c d", "a"),
+        ]
+        .into_iter()
+        .map(|(src, trg)| BiText::new(String::from(src), None, Some(String::from(trg)), None))
+        .collect::<Vec<_>>();
+
+        let cleaner = LengthFilter::new(3, 6, LengthFilterUnit::Word);
+        let (kept, rejected) = cleaner.filter_with_rejects(test_vectors.clone());
+        assert_eq!(kept.len(), 0);
+        assert_eq!(rejected[0].reason, "source length 2 words < 3");
+        assert_eq!(rejected[1].reason, "target length 10 words > 6");
+
+        let cleaner = LengthFilter::new(3, 6, LengthFilterUnit::Word).with_target_length(1, 10);
+        assert_eq!(cleaner.filter_text(test_vectors.clone()).len(), 2);
+
+        let cleaner = LengthFilter::new(3, 6, LengthFilterUnit::Word).with_side(Side::Target);
+        assert_eq!(cleaner.filter_text(test_vectors.clone()).len(), 1);
+        let untranslated = BiText::new(String::from("a b c d"), None, None, None);
+        assert_eq!(cleaner.reject_reason(&untranslated), Some(String::from("target is missing")));
+        assert_eq!(LengthFilter::new(3, 6, LengthFilterUnit::Word).reject_reason(&untranslated), None);
+
+        let cleaner = LengthFilter::new(3, 6, LengthFilterUnit::Word).with_side(Side::Either);
+        assert_eq!(cleaner.filter_text(test_vectors).len(), 3);
+    }
+
+    #[test]
+    fn test_long_word_filter_target() {
+        let test_vectors = vec![BiText::new(String::from("Okay"), None, Some(String::from("Baaaaaaaaaaad")), None)];
+        assert_eq!(LongWordFilter::new(10).filter_text(test_vectors.clone()).len(), 0);
+        assert_eq!(LongWordFilter::new(10).with_side(Side::Source).filter_text(test_vectors.clone()).len(), 1);
+        assert_eq!(LongWordFilter::new(10).with_target_threshold(20).filter_text(test_vectors).len(), 1);
+    }
+
+    #[test]
+    fn test_filter_scores() {
+        let bitext = BiText::new(String::from("a bb ccc"), None, Some(String::from("a bbbbb")), None);
+        assert_eq!(LengthRatioFilter::new(0.8, LengthFilterUnit::Word).score(&bitext), Some(Score::Pair(2.0 / 3.0)));
+        assert_eq!(LongWordFilter::new(10).score(&bitext), Some(Score::Sides { source: Some(3.0), target: Some(5.0) }));
+        assert_eq!(LongWordFilter::new(10).with_side(Side::Target).score(&bitext), Some(Score::Sides { source: None, target: Some(5.0) }));
+        assert!(!Filter::can_score(&LongWordFilter::new(10).with_side(Side::Either)));
+        assert_eq!(LengthFilter::new(1, 5, LengthFilterUnit::Word).score(&bitext), Some(Score::Sides { source: Some(3.0), target: Some(2.0) }));
+        assert_eq!(RegExpFilter::new("c+", false).score(&bitext), Some(Score::Pair(0.0)));
+
+        let untranslated = BiText::new(String::from("a bb ccc"), None, None, None);
+        assert!(LengthRatioFilter::new(0.8, LengthFilterUnit::Word).reject_reason(&untranslated).is_some());
+        assert_eq!(LengthRatioFilter::new(0.8, LengthFilterUnit::Word).score(&untranslated), Some(Score::Pair(0.0)));
+    }
+
+    #[test]
+    fn test_threshold_filter() {
+        let mut bitexts = vec![BiText::new(String::from("a"), None, None, None); 3];
+        bitexts[0].scores.insert(String::from("length_ratio_filter"), 0.5);
+        bitexts[1].scores.insert(String::from("length_ratio_filter"), 0.9);
+        bitexts[1].scores.insert(String::from("long_word_filter"), 40.0);
+        let cleaner = ThresholdFilter::new(
+            BTreeMap::from([(String::from("length_ratio_filter"), 0.8)]),
+            BTreeMap::from([(String::from("long_word_filter"), 30.0)]),
+        );
+        let (kept, rejected) = cleaner.filter_with_rejects(bitexts);
+        assert_eq!(kept.len(), 1);
+        assert_eq!(rejected[0].reason, "length_ratio_filter score 0.50 < 0.8");
+        assert_eq!(rejected[1].reason, "long_word_filter score 40.00 > 30");
+    }
+
     #[test]
     fn test_regexp_filter_accept() {
         let test_vectors = vec![
@@ -525,11 +1026,35 @@ mod tests {
         .into_iter()
         .map(|x| BiText::new(String::from(x), None, None, None))
         .collect();
-        let cleaner = LangIdFilter::new(String::from("English"));
+        let cleaner = LangIdFilter::new(Some(Language::English), None, DetectorOptions::default());
         let cleaned = cleaner.filter_text(test_vectors);
         assert_eq!(cleaned.len(), 2)
     }
 
+    #[test]
+    fn test_langid_filter_bilingual() {
+        let bitext = |src: &str, src_lang: &str, trg: &str| {
+            BiText::new(String::from(src), Some(String::from(src_lang)), Some(String::from(trg)), Some(String::from("de")))
+        };
+        let test_vectors = vec![
+            bitext("This is a simple test", "en", "Das ist ein Test"),
+            bitext("This is a simple test", "en", "This is not German"),
+            bitext("Das ist ein Test", "en-US", "Das ist ein Test"),
+            bitext("This is a simple test", "en", "Ok"),
+        ];
+        let cleaner = LangIdFilter::new(Some(Language::French), None, DetectorOptions::default()).with_short_length(5);
+        let scores: Vec<bool> = test_vectors
+            .iter()
+            .map(|x| matches!(cleaner.score(x), Some(Score::Pair(score)) if score >= 0.5))
+            .collect();
+        assert_eq!(scores, vec![true, false, false, true]);
+        let (kept, rejected) = cleaner.filter_with_rejects(test_vectors);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(rejected[0].reason, "target detected English, expected German");
+        assert_eq!(rejected[1].reason, "source detected German, expected English");
+    }
+
+
     #[test]
     fn levenshtein() {
         let test_vectors: Vec<BiText> = vec![
@@ -550,11 +1075,23 @@ mod tests {
             )
         })
         .collect();
-        let cleaner = SimilarityFilter::new(2, false);
+        let cleaner = SimilarityFilter::new(0.8, LengthFilterUnit::Char);
         let cleaned = cleaner.filter_text(test_vectors.clone());
         assert_eq!(cleaned.len(), 2);
-        let cleaner = SimilarityFilter::new(2, false);
+        let normalization = Normalization { lowercase: true, ..Normalization::default() };
+        let cleaner = SimilarityFilter::new(0.8, LengthFilterUnit::Char).with_normalization(normalization);
         let cleaned = cleaner.filter_text(test_vectors);
         assert_eq!(cleaned.len(), 1);
     }
+
+    #[test]
+    fn test_similarity_filter_words() {
+        let bitext = BiText::new(String::from("The cat sat on the mat."), None, Some(String::from("The cat sat on a mat!")), None);
+        let cleaner = SimilarityFilter::new(0.8, LengthFilterUnit::Word);
+        assert_eq!(cleaner.score(&bitext), Some(Score::Pair(1.0 - 2.0 / 7.0)));
+        let normalization = Normalization { lowercase: true, punctuation: true, ..Normalization::default() };
+        let cleaner = cleaner.with_normalization(normalization);
+        assert_eq!(cleaner.score(&bitext), Some(Score::Pair(1.0 - 1.0 / 6.0)));
+        assert_eq!(edit_distance(&[1, 2, 3], &[1, 3, 4]), 2);
+    }
 }
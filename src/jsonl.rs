@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Lines, Write};
+use serde_json::value::RawValue;
+use crate::compression;
+use crate::model::BiText;
+
+/// Prefix of the metadata keys holding fields of the input that are not part of
+/// [`BiText`]. Their values are kept as raw JSON and written back unchanged.
+const PASSTHROUGH_PREFIX: &str = "json:";
+
+/// Names of the JSON fields holding the parts of a [`BiText`].
+#[derive(Debug, Clone)]
+pub struct JsonlFields {
+    pub src: String,
+    pub tgt: String,
+    pub src_lang: String,
+    pub tgt_lang: String,
+    /// Object with the scores of filters that ran in score mode.
+    pub scores: String,
+}
+
+impl Default for JsonlFields {
+    fn default() -> Self {
+        JsonlFields {
+            src: String::from("src"),
+            tgt: String::from("tgt"),
+            src_lang: String::from("src_lang"),
+            tgt_lang: String::from("tgt_lang"),
+            scores: String::from("scores"),
+        }
+    }
+}
+
+impl JsonlFields {
+    fn contains(&self, field: &str) -> bool {
+        field == self.src || field == self.tgt || field == self.src_lang || field == self.tgt_lang || field == self.scores
+    }
+}
+
+#[derive(Debug)]
+pub enum JsonlError {
+    Io(io::Error),
+    Json { line: usize, source: serde_json::Error },
+    InvalidField { line: usize, field: String },
+}
+
+impl fmt::Display for JsonlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonlError::Io(source) => write!(f, "Could not read JSONL file: {}", source),
+            JsonlError::Json { line, source } => write!(f, "Invalid JSON on line {}: {}", line, source),
+            JsonlError::InvalidField { line, field } => {
+                write!(f, "Field '{}' on line {} is missing, null or not a string", field, line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JsonlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            JsonlError::Io(source) => Some(source),
+            JsonlError::Json { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Lazily reads one JSON object per line as a [`BiText`]. Empty lines are skipped.
+pub struct JsonlReader<R: BufRead> {
+    lines: Lines<R>,
+    fields: JsonlFields,
+    line: usize,
+}
+
+impl JsonlReader<Box<dyn BufRead>> {
+    pub fn open(file: &str, fields: JsonlFields) -> Result<Self, JsonlError> {
+        let file = compression::open(file).map_err(JsonlError::Io)?;
+        Ok(JsonlReader::new(file, fields))
+    }
+}
+
+impl<R: BufRead> JsonlReader<R> {
+    pub fn new(reader: R, fields: JsonlFields) -> Self {
+        JsonlReader {
+            lines: reader.lines(),
+            fields,
+            line: 0,
+        }
+    }
+
+    fn parse(&self, line: &str) -> Result<BiText, JsonlError> {
+        let object: BTreeMap<String, Box<RawValue>> =
+            serde_json::from_str(line).map_err(|source| JsonlError::Json { line: self.line, source })?;
+        let invalid = |field: &String| JsonlError::InvalidField { line: self.line, field: field.clone() };
+        // Missing fields and null are both None.
+        let string = |field: &String| -> Result<Option<String>, JsonlError> {
+            match object.get(field) {
+                Some(value) => serde_json::from_str(value.get()).map_err(|_| invalid(field)),
+                None => Ok(None),
+            }
+        };
+        let text = string(&self.fields.src)?.ok_or_else(|| invalid(&self.fields.src))?;
+        let mut bitext = BiText::new(
+            text,
+            string(&self.fields.src_lang)?,
+            string(&self.fields.tgt)?,
+            string(&self.fields.tgt_lang)?,
+        );
+        if let Some(scores) = object.get(&self.fields.scores) {
+            bitext.scores = serde_json::from_str(scores.get())
+                .map_err(|_| JsonlError::InvalidField { line: self.line, field: self.fields.scores.clone() })?;
+        }
+        for (field, value) in object.iter() {
+            if !self.fields.contains(field) {
+                bitext.metadata.insert(format!("{}{}", PASSTHROUGH_PREFIX, field), String::from(value.get()));
+            }
+        }
+        bitext.line = Some(self.line as u64);
+        Ok(bitext)
+    }
+}
+
+impl<R: BufRead> Iterator for JsonlReader<R> {
+    type Item = Result<BiText, JsonlError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(error) => return Some(Err(JsonlError::Io(error))),
+            };
+            self.line += 1;
+            if !line.trim().is_empty() {
+                return Some(self.parse(&line));
+            }
+        }
+    }
+}
+
+/// Writes one JSON object per [`BiText`].
+///
+/// Fields passed through by [`JsonlReader`] are written unchanged, all other
+/// metadata is written as string fields.
+pub struct JsonlWriter<W: Write> {
+    writer: W,
+    fields: JsonlFields,
+}
+
+impl<W: Write> JsonlWriter<W> {
+    pub fn new(writer: W, fields: JsonlFields) -> Self {
+        JsonlWriter { writer, fields }
+    }
+
+    pub fn write(&mut self, bitext: &BiText) -> io::Result<()> {
+        let mut entries: Vec<(&str, String)> = vec![(&self.fields.src, serde_json::to_string(&bitext.text)?)];
+        let optional = [
+            (&self.fields.tgt, &bitext.translation),
+            (&self.fields.src_lang, &bitext.language),
+            (&self.fields.tgt_lang, &bitext.translation_language),
+        ];
+        for (field, value) in optional {
+            if let Some(value) = value {
+                entries.push((field, serde_json::to_string(value)?));
+            }
+        }
+        if !bitext.scores.is_empty() {
+            entries.push((&self.fields.scores, serde_json::to_string(&bitext.scores)?));
+        }
+        for (key, value) in bitext.metadata.iter() {
+            let entry = match key.strip_prefix(PASSTHROUGH_PREFIX) {
+                Some(field) => (field, value.clone()),
+                None => (key.as_str(), serde_json::to_string(value)?),
+            };
+            if !self.fields.contains(entry.0) && !entries.iter().any(|(field, _)| *field == entry.0) {
+                entries.push(entry);
+            }
+        }
+
+        let mut line = String::from("{");
+        for (index, (field, value)) in entries.iter().enumerate() {
+            if index > 0 {
+                line.push(',');
+            }
+            line.push_str(&serde_json::to_string(field)?);
+            line.push(':');
+            line.push_str(value);
+        }
+        line.push('}');
+        writeln!(self.writer, "{}", line)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jsonl_reader() {
+        let input = "{\"src\": \"Hello\", \"tgt\": \"Hallo\", \"src_lang\": \"en\", \"tgt_lang\": \"de\", \"score\": 0.5, \"meta\": {\"b\": 1, \"a\": [2]}}\n\n";
+        let bitexts: Vec<BiText> = JsonlReader::new(input.as_bytes(), JsonlFields::default())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(bitexts.len(), 1);
+        assert_eq!(bitexts[0].text, "Hello");
+        assert_eq!(bitexts[0].translation, Some(String::from("Hallo")));
+        assert_eq!(bitexts[0].language, Some(String::from("en")));
+        assert_eq!(bitexts[0].metadata.get("json:score"), Some(&String::from("0.5")));
+        assert_eq!(bitexts[0].metadata.get("json:meta"), Some(&String::from("{\"b\": 1, \"a\": [2]}")));
+    }
+
+    #[test]
+    fn test_jsonl_writer_passthrough() {
+        let input = "{\"source\":\"Hello \\\"you\\\"\",\"target\":\"Hallo\",\"meta\":{\"b\":1,\"a\":[2]}}\n";
+        let fields = JsonlFields {
+            src: String::from("source"),
+            tgt: String::from("target"),
+            ..JsonlFields::default()
+        };
+        let bitexts: Vec<BiText> = JsonlReader::new(input.as_bytes(), fields.clone())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut writer = JsonlWriter::new(Vec::new(), fields);
+        writer.write(&bitexts[0]).unwrap();
+        assert_eq!(String::from_utf8(writer.finish().unwrap()).unwrap(), input);
+    }
+
+    #[test]
+    fn test_jsonl_reader_errors() {
+        let input = "{\"tgt\": \"Hallo\"}\n{\"src\": 1}\nnot json\n{\"src\": null}\n{\"src\": \"Hi\", \"tgt\": null}\n";
+        let results: Vec<Result<BiText, JsonlError>> = JsonlReader::new(input.as_bytes(), JsonlFields::default()).collect();
+        assert!(matches!(&results[0], Err(JsonlError::InvalidField { line: 1, .. })));
+        assert!(matches!(&results[1], Err(JsonlError::InvalidField { line: 2, .. })));
+        assert!(matches!(&results[2], Err(JsonlError::Json { line: 3, .. })));
+        assert!(matches!(&results[3], Err(JsonlError::InvalidField { line: 4, .. })));
+        assert_eq!(results[4].as_ref().unwrap().translation, None);
+    }
+
+    #[test]
+    fn test_jsonl_scores() {
+        let input = "{\"src\":\"Hello\",\"scores\":{\"length_ratio_filter\":0.5}}\n";
+        let bitexts: Vec<BiText> = JsonlReader::new(input.as_bytes(), JsonlFields::default())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(bitexts[0].scores.get("length_ratio_filter"), Some(&0.5));
+        let mut writer = JsonlWriter::new(Vec::new(), JsonlFields::default());
+        writer.write(&bitexts[0]).unwrap();
+        assert_eq!(String::from_utf8(writer.finish().unwrap()).unwrap(), input);
+    }
+}
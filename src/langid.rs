@@ -0,0 +1,188 @@
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use lingua::{IsoCode639_1, Language, LanguageDetector, LanguageDetectorBuilder};
+use rayon::prelude::*;
+use crate::model::BiText;
+
+/// Detectors in use, by their options. Only weak references are kept, so a
+/// detector is dropped with the last step using it.
+static DETECTORS: OnceLock<Mutex<HashMap<DetectorOptions, Weak<SharedDetector>>>> = OnceLock::new();
+
+/// A detector shared by all steps built with the same [`DetectorOptions`].
+///
+/// lingua keeps the language models of all detectors in a global cache, so
+/// they are unloaded when the last step using the detector is dropped. Other
+/// detectors load the models they still need again.
+pub struct SharedDetector(LanguageDetector);
+
+impl Deref for SharedDetector {
+    type Target = LanguageDetector;
+
+    fn deref(&self) -> &LanguageDetector {
+        &self.0
+    }
+}
+
+impl Drop for SharedDetector {
+    fn drop(&mut self) {
+        self.0.unload_language_models();
+    }
+}
+
+/// Parses a language name like `English` or an ISO 639-1 code like `en` or `en-US`.
+pub fn parse_language(lang: &str) -> Option<Language> {
+    if let Ok(language) = Language::from_str(lang) {
+        return Some(language);
+    }
+    let code = lang.split(['-', '_']).next()?;
+    let code = IsoCode639_1::from_str(&code.to_lowercase()).ok()?;
+    Some(Language::from_iso_code_639_1(&code))
+}
+
+/// How a language detector is built.
+///
+/// Building a detector is expensive, so all steps asking for a detector with
+/// the same options share one while any of them exists.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct DetectorOptions {
+    /// The languages the detector chooses from, all languages if empty.
+    pub languages: BTreeSet<Language>,
+    /// Faster, but less accurate on short segments.
+    pub low_accuracy: bool,
+    /// Loads all language models when the detector is built instead of on first use.
+    pub preload: bool,
+}
+
+impl DetectorOptions {
+    pub fn is_candidate(&self, lang: Language) -> bool {
+        self.languages.is_empty() || self.languages.contains(&lang)
+    }
+
+    /// Returns the shared detector for these options, building it if no step
+    /// uses one.
+    pub fn detector(&self) -> Arc<SharedDetector> {
+        let mut detectors = DETECTORS.get_or_init(Default::default).lock().unwrap();
+        detectors.retain(|_, detector| detector.strong_count() > 0);
+        if let Some(detector) = detectors.get(self).and_then(Weak::upgrade) {
+            return detector;
+        }
+        let mut builder = match self.languages.is_empty() {
+            true => LanguageDetectorBuilder::from_all_languages(),
+            false => LanguageDetectorBuilder::from_languages(&self.languages.iter().copied().collect::<Vec<_>>()),
+        };
+        if self.low_accuracy {
+            builder.with_low_accuracy_mode();
+        }
+        if self.preload {
+            builder.with_preloaded_language_models();
+        }
+        let detector = Arc::new(SharedDetector(builder.build()));
+        detectors.insert(self.clone(), Arc::downgrade(&detector));
+        detector
+    }
+}
+
+/// Writes the detected language and its confidence for both sides into
+/// [`BiText::metadata`], as `langid:src`, `langid:src_confidence`,
+/// `langid:trg` and `langid:trg_confidence`.
+///
+/// Languages are written as ISO 639-1 codes. Nothing is written for a side
+/// without a detected language.
+pub struct LangIdAnnotator {
+    model: Arc<SharedDetector>,
+    fill_languages: bool,
+}
+
+impl LangIdAnnotator {
+    pub fn new(options: &DetectorOptions) -> Self {
+        LangIdAnnotator { model: options.detector(), fill_languages: false }
+    }
+
+    /// Also sets the languages of pairs that have none to the detected ones.
+    pub fn with_fill_languages(mut self, fill_languages: bool) -> Self {
+        self.fill_languages = fill_languages;
+        self
+    }
+
+    fn detect(&self, text: &str) -> Option<(String, f64)> {
+        let (language, confidence) = self.model.compute_language_confidence_values(text).into_iter().next()?;
+        Some((language.iso_code_639_1().to_string(), confidence))
+    }
+
+    pub fn annotate(&self, bitext: &mut BiText) {
+        let sides = [("src", Some(&bitext.text)), ("trg", bitext.translation.as_ref())];
+        let detected: Vec<(&str, String, f64)> = sides
+            .into_iter()
+            .filter_map(|(side, text)| {
+                let (language, confidence) = self.detect(text?)?;
+                Some((side, language, confidence))
+            })
+            .collect();
+        for (side, language, confidence) in detected {
+            let field = if side == "src" { &mut bitext.language } else { &mut bitext.translation_language };
+            if self.fill_languages && field.is_none() {
+                *field = Some(language.clone());
+            }
+            bitext.metadata.insert(format!("langid:{}", side), language);
+            bitext.metadata.insert(format!("langid:{}_confidence", side), format!("{:.4}", confidence));
+        }
+    }
+
+    pub fn annotate_chunk(&self, mut bitext: Vec<BiText>) -> Vec<BiText> {
+        bitext.par_iter_mut().for_each(|x| self.annotate(x));
+        bitext
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_language() {
+        assert_eq!(parse_language("English"), Some(Language::English));
+        assert_eq!(parse_language("de"), Some(Language::German));
+        assert_eq!(parse_language("en-GB"), Some(Language::English));
+        assert_eq!(parse_language("xx"), None);
+    }
+
+    #[test]
+    fn test_shared_detector() {
+        let options = DetectorOptions {
+            languages: BTreeSet::from([Language::English, Language::German]),
+            ..DetectorOptions::default()
+        };
+        assert!(Arc::ptr_eq(&options.detector(), &options.clone().detector()));
+        assert!(!options.is_candidate(Language::French));
+
+        let low_accuracy = DetectorOptions { low_accuracy: true, ..options.clone() };
+        assert!(!Arc::ptr_eq(&options.detector(), &low_accuracy.detector()));
+
+        let unused = DetectorOptions {
+            languages: BTreeSet::from([Language::Dutch, Language::Italian]),
+            low_accuracy: true,
+            preload: false,
+        };
+        let detector = Arc::downgrade(&unused.detector());
+        assert!(detector.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_langid_annotator() {
+        let bitexts = vec![
+            BiText::new(String::from("This is a simple test"), None, Some(String::from("Das ist ein Test")), None),
+            BiText::new(String::from("Das ist ein Test"), Some(String::from("en")), None, None),
+        ];
+        let annotator = LangIdAnnotator::new(&DetectorOptions::default()).with_fill_languages(true);
+        let annotated = annotator.annotate_chunk(bitexts);
+        assert_eq!(annotated[0].language.as_deref(), Some("en"));
+        assert_eq!(annotated[0].translation_language.as_deref(), Some("de"));
+        assert_eq!(annotated[0].metadata.get("langid:trg").map(String::as_str), Some("de"));
+        assert!(annotated[0].metadata.contains_key("langid:src_confidence"));
+        assert_eq!(annotated[1].language.as_deref(), Some("en"));
+        assert_eq!(annotated[1].metadata.get("langid:src").map(String::as_str), Some("de"));
+        assert!(!annotated[1].metadata.contains_key("langid:trg"));
+    }
+}
@@ -1,12 +1,29 @@
 use crate::cleaner::*;
 use crate::filter::*;
 use crate::model::BiText;
+use pyo3::exceptions::{PyTypeError, PyValueError};
 use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyList};
+use serde_yaml::Value;
 
 mod cleaner;
 mod filter;
+mod langid;
 mod model;
+mod normalize;
+mod compression;
 mod moses;
+mod tmx;
+mod tsv;
+mod jsonl;
+mod corpus;
+mod configparser;
+mod pipelines;
+mod deduplicator;
+mod minhash;
+mod contamination;
+mod step;
+mod stats;
 
 /// Formats the sum of two numbers as string.
 #[pyfunction]
@@ -20,6 +37,87 @@ fn run_pipeline(strings: BiText) -> PyResult<BiText> {
     Ok(strings)
 }
 
+/// Converts the parameters of a step from Python into the config representation.
+fn to_value(value: &PyAny) -> PyResult<Value> {
+    if value.is_none() {
+        Ok(Value::Null)
+    } else if let Ok(value) = value.downcast::<PyBool>() {
+        Ok(Value::Bool(value.is_true()))
+    } else if let Ok(value) = value.extract::<i64>() {
+        Ok(Value::Number(value.into()))
+    } else if let Ok(value) = value.extract::<f64>() {
+        Ok(Value::Number(value.into()))
+    } else if let Ok(value) = value.extract::<String>() {
+        Ok(Value::String(value))
+    } else if let Ok(value) = value.downcast::<PyList>() {
+        value.iter().map(to_value).collect::<PyResult<_>>().map(Value::Sequence)
+    } else if let Ok(value) = value.downcast::<PyDict>() {
+        value
+            .iter()
+            .map(|(key, value)| Ok((to_value(key)?, to_value(value)?)))
+            .collect::<PyResult<_>>()
+            .map(Value::Mapping)
+    } else {
+        Err(PyTypeError::new_err(format!("Unsupported parameter value {}", value)))
+    }
+}
+
+/// A pipeline of steps, built from a YAML config or step by step.
+#[pyclass(name = "Pipeline")]
+struct PyPipeline {
+    pipeline: pipelines::Pipeline,
+}
+
+#[pymethods]
+impl PyPipeline {
+    #[new]
+    fn py_new() -> Self {
+        PyPipeline { pipeline: pipelines::Pipeline::new() }
+    }
+
+    #[staticmethod]
+    fn from_yaml(config: &str) -> PyResult<Self> {
+        let pipeline = configparser::PipelineConfig::from_str(config)
+            .and_then(|x| pipelines::Pipeline::from_config(&x))
+            .map_err(|x| PyValueError::new_err(x.to_string()))?;
+        Ok(PyPipeline { pipeline })
+    }
+
+    fn add_step(&mut self, name: &str, params: Option<&PyAny>) -> PyResult<()> {
+        let params = match params {
+            Some(params) => to_value(params)?,
+            None => Value::Null,
+        };
+        let step = configparser::build_step(&configparser::StepConfig::new(name, params))
+            .map_err(|x| PyValueError::new_err(x.to_string()))?;
+        self.pipeline.add_step(step);
+        Ok(())
+    }
+
+    /// Cleans one chunk of a corpus. Steps like deduplicators with
+    /// `store: disk` hold pairs back until [`PyPipeline::finish`] is called.
+    fn run(&mut self, py: Python<'_>, bitexts: Vec<BiText>) -> PyResult<Vec<BiText>> {
+        py.allow_threads(|| self.pipeline.run(bitexts, &mut Vec::new())).map_err(PyErr::from)
+    }
+
+    /// Returns the pairs held back by the steps, to be called once after the
+    /// last chunk of a corpus.
+    fn finish(&mut self, py: Python<'_>) -> PyResult<Vec<BiText>> {
+        py.allow_threads(|| {
+            let mut bitexts = Vec::new();
+            while let Some(held_back) = self.pipeline.finish(&mut Vec::new())? {
+                bitexts.extend(held_back);
+            }
+            Ok(bitexts)
+        })
+    }
+
+    /// The statistics of all runs so far as JSON.
+    fn report(&self) -> String {
+        self.pipeline.report().to_json()
+    }
+}
+
 /// A Python module implemented in Rust. The name of this function must match
 /// the `lib.name` setting in the `Cargo.toml`, else Python will not be able to
 /// import the module.
@@ -28,6 +126,7 @@ fn bitextcleaner(_py: Python<'_>, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(sum_as_string, m)?)?;
     m.add_function(wrap_pyfunction!(run_pipeline, m)?)?;
     m.add_class::<BiText>();
+    m.add_class::<PyPipeline>()?;
     //m.add_function((BiText::py_new, m)?)?;
     Ok(())
 }
@@ -1,32 +1,212 @@
 extern crate clap;
 
-use std::time::Instant;
-use clap::{Parser, Subcommand};
+use std::error::Error;
+use std::fs;
+use std::process;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use filter::Rejection;
+use model::BiText;
 
 mod cleaner;
 mod filter;
+mod langid;
 mod model;
+mod normalize;
+mod compression;
 mod moses;
+mod tmx;
+mod tsv;
+mod jsonl;
+mod corpus;
 mod configparser;
 mod pipelines;
 mod deduplicator;
+mod minhash;
+mod contamination;
+mod step;
+mod stats;
 
 fn main() {
-    let app = CliArgs::parse();
-    println!("{}", app.src_file);
-    let now = Instant::now();
-    let mut bitext = moses::align_moses(&*app.src_file, &*app.trg_file, Some(app.src_lang), Some(app.trg_lang));
-    println!("{}", &bitext.len());
-    bitext = pipelines::default_pipeline(bitext);
-    println!("{}", &bitext.len());
-    println!("{}", now.elapsed().as_secs());
+    let app = parse_args();
+    // Steps clean up after themselves when they are dropped, which
+    // process::exit skips, so the pipeline must be gone before exiting.
+    if let Err(error) = run(&app) {
+        exit_with_error(error);
+    }
+}
+
+fn run(app: &CliArgs) -> Result<(), Box<dyn Error>> {
+    let reader = corpus::open_reader(
+        app.input_format,
+        &app.src_file,
+        app.trg_file.as_deref(),
+        app.src_lang.clone(),
+        app.trg_lang.clone(),
+        app.on_mismatch,
+    )?;
+    let mut writer = create_writer(app)?;
+    let mut rejects_writer = app.rejects.as_deref().map(corpus::RejectsWriter::create).transpose()?;
+    let config = match &app.config {
+        Some(config) => configparser::parse_config(config),
+        None => configparser::PipelineConfig::from_str(pipelines::DEFAULT_PIPELINE),
+    };
+    let mut pipeline = config.and_then(|x| pipelines::Pipeline::from_config(&x))?;
+    let mut write = |bitext: Vec<BiText>, rejects: Vec<Rejection>| -> Result<(), Box<dyn Error>> {
+        if let Some(writer) = writer.as_mut() {
+            bitext.iter().try_for_each(|x| writer.write(x))?;
+        }
+        if let Some(writer) = rejects_writer.as_mut() {
+            rejects.iter().try_for_each(|x| writer.write(x))?;
+        }
+        Ok(())
+    };
+    let mut read_error = None;
+    let reader = reader.map_while(|bitext| bitext.map_err(|error| read_error = Some(error)).ok());
+    for chunk in pipelines::chunked(reader, app.chunk_size) {
+        let mut rejects = Vec::new();
+        let bitext = pipeline.run(chunk, &mut rejects)?;
+        write(bitext, rejects)?;
+    }
+    if let Some(error) = read_error {
+        return Err(error);
+    }
+    loop {
+        let mut rejects = Vec::new();
+        let bitext = pipeline.finish(&mut rejects)?;
+        let finished = bitext.is_none();
+        write(bitext.unwrap_or_default(), rejects)?;
+        if finished {
+            break;
+        }
+    }
+    if let Some(writer) = writer {
+        writer.finish()?;
+    }
+    if let Some(writer) = rejects_writer {
+        writer.finish()?;
+    }
+    let report = pipeline.report().to_json();
+    match &app.report {
+        Some(file) => fs::write(file, report)?,
+        None => eprintln!("{}", report),
+    }
+    Ok(())
+}
+
+/// Moses output is written to `--out-src` and `--out-trg`, all other formats to `--output`.
+/// Without an output format, it is guessed from the extension of the output file,
+/// otherwise the input format is used, or TSV for Moses input.
+fn create_writer(app: &CliArgs) -> Result<Option<corpus::BiTextWriter>, Box<dyn Error>> {
+    match (&app.output, &app.out_src, &app.out_trg) {
+        (None, None, None) => Ok(None),
+        (Some(output), None, None) => {
+            let format = match app.output_format.or_else(|| corpus::Format::from_path(output)) {
+                Some(format) => format,
+                None if app.input_format == corpus::Format::Moses => corpus::Format::Tsv,
+                None => app.input_format,
+            };
+            Ok(Some(corpus::BiTextWriter::create(format, output, app.src_lang.as_deref())?))
+        }
+        (None, Some(out_src), Some(out_trg)) => Ok(Some(corpus::BiTextWriter::create_moses(out_src, out_trg)?)),
+        _ => Err("Use either --output or both --out-src and --out-trg".into()),
+    }
+}
+
+/// Parses the arguments, moving the deprecated positional languages to
+/// `--src-lang` and `--trg-lang`.
+fn parse_args() -> CliArgs {
+    let mut app = CliArgs::parse();
+    if app.legacy_src_lang.is_some() || app.legacy_trg_lang.is_some() {
+        eprintln!("Passing the languages as positional arguments is deprecated, use --src-lang and --trg-lang");
+        app.src_lang = app.src_lang.or(app.legacy_src_lang.take());
+        app.trg_lang = app.trg_lang.or(app.legacy_trg_lang.take());
+    }
+    if let Err(error) = check_args(&app) {
+        error.exit();
+    }
+    app
+}
+
+/// Rejects reading or writing both sides of a Moses corpus from the same
+/// stream, which would mix up source and target.
+fn check_args(app: &CliArgs) -> Result<(), clap::Error> {
+    if app.input_format == corpus::Format::Moses && app.src_file == "-" && app.trg_file.as_deref() == Some("-") {
+        return Err(CliArgs::command()
+            .error(ErrorKind::ArgumentConflict, "Source and target of Moses input can not both be read from stdin"));
+    }
+    if app.out_src.as_deref() == Some("-") && app.out_trg.as_deref() == Some("-") {
+        return Err(CliArgs::command()
+            .error(ErrorKind::ArgumentConflict, "Source and target of Moses output can not both be written to stdout"));
+    }
+    Ok(())
+}
+
+fn exit_with_error(error: impl std::fmt::Display) -> ! {
+    eprintln!("{}", error);
+    process::exit(1)
 }
 
 #[derive(Parser, Default, Debug)]
 struct CliArgs {
+    /// Source file for Moses input, otherwise the corpus file, `-` for stdin
     src_file: String,
-    trg_file: String,
-    src_lang: String,
-    trg_lang: String,
+    /// Target file for Moses input
+    trg_file: Option<String>,
+    /// Deprecated, use --src-lang
+    #[arg(hide = true, conflicts_with = "src_lang")]
+    legacy_src_lang: Option<String>,
+    /// Deprecated, use --trg-lang
+    #[arg(hide = true, conflicts_with = "trg_lang")]
+    legacy_trg_lang: Option<String>,
+    /// Source language, used when the input does not specify one
+    #[arg(long)]
+    src_lang: Option<String>,
+    /// Target language, used when the input does not specify one
+    #[arg(long)]
+    trg_lang: Option<String>,
+    /// Input format: moses, tsv, csv, jsonl or tmx
+    #[arg(long, default_value = "moses")]
+    input_format: corpus::Format,
+    /// Output file for the cleaned corpus, `-` for stdout
+    #[arg(long)]
+    output: Option<String>,
+    /// Output format for --output: tsv, csv, jsonl or tmx
+    #[arg(long)]
+    output_format: Option<corpus::Format>,
+    /// Source file of the cleaned corpus in Moses format, `-` for stdout
+    #[arg(long)]
+    out_src: Option<String>,
+    /// Target file of the cleaned corpus in Moses format, `-` for stdout
+    #[arg(long)]
+    out_trg: Option<String>,
+    /// YAML file with the pipeline steps, the default pipeline is used without it
+    #[arg(long)]
+    config: Option<String>,
+    /// Writes the pairs removed by filters to this TSV file, with the filter and the reason
+    #[arg(long)]
+    rejects: Option<String>,
+    /// Number of pairs that are read and processed at once
+    #[arg(long, default_value_t = 100_000)]
+    chunk_size: usize,
+    /// What to do if source and target have a different number of lines: error, truncate or pad
+    #[arg(long, default_value = "error")]
+    on_mismatch: moses::MismatchPolicy,
+    /// Writes the statistics of every step as JSON to this file, otherwise they are printed to stderr
+    #[arg(long)]
+    report: Option<String>,
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_args() {
+        let check = |args: &[&str]| check_args(&CliArgs::try_parse_from(args).unwrap()).map_err(|x| x.kind());
+        assert_eq!(check(&["bitextcleaner", "src.txt", "trg.txt", "--out-src", "-", "--out-trg", "trg.out"]), Ok(()));
+        assert_eq!(check(&["bitextcleaner", "src.txt", "trg.txt", "--out-src", "-", "--out-trg", "-"]), Err(ErrorKind::ArgumentConflict));
+        assert_eq!(check(&["bitextcleaner", "-", "-"]), Err(ErrorKind::ArgumentConflict));
+        assert_eq!(check(&["bitextcleaner", "-", "--input-format", "tsv"]), Ok(()));
+    }
+}
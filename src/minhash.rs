@@ -0,0 +1,310 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use rayon::prelude::*;
+use unicode_segmentation::UnicodeSegmentation;
+use crate::deduplicator::{ClusterTracker, KeySide};
+use crate::filter::{LengthFilterUnit, Rejection};
+use crate::model::BiText;
+use crate::normalize::Normalization;
+use crate::stats::DuplicateCluster;
+
+/// Removes near duplicates, e.g. pages of a template that only differ by a
+/// date or a product name.
+///
+/// Texts are split into shingles of `shingle_size` words or characters, and
+/// pairs whose shingle sets have a Jaccard similarity of at least `threshold`
+/// with an earlier pair are removed. The similarity is estimated with MinHash
+/// signatures, and locality-sensitive hashing over bands of the signatures
+/// finds the earlier pairs to compare with, so a few near duplicates close to
+/// the threshold can be missed.
+///
+/// Signatures of all kept pairs stay in memory, which takes 4 bytes per hash
+/// function and pair.
+pub struct NearDeduplicator {
+    threshold: f64,
+    side: KeySide,
+    unit: LengthFilterUnit,
+    shingle_size: usize,
+    bands: usize,
+    rows: usize,
+    normalization: Normalization,
+    index: Mutex<MinHashIndex>,
+    clusters: Option<ClusterTracker>,
+}
+
+#[derive(Default)]
+struct MinHashIndex {
+    /// Per band, the kept pairs by the hash of their signature in that band.
+    buckets: Vec<HashMap<u64, Vec<usize>>>,
+    signatures: Vec<Vec<u32>>,
+}
+
+impl NearDeduplicator {
+    pub fn new(threshold: f64) -> Self {
+        NearDeduplicator {
+            threshold,
+            side: KeySide::Pair,
+            unit: LengthFilterUnit::Word,
+            shingle_size: 3,
+            bands: 0,
+            rows: 0,
+            normalization: Normalization::default(),
+            index: Mutex::new(MinHashIndex::default()),
+            clusters: None,
+        }
+        .with_num_hashes(128)
+    }
+
+    pub fn with_side(mut self, side: KeySide) -> Self {
+        self.side = side;
+        self
+    }
+
+    pub fn with_shingles(mut self, unit: LengthFilterUnit, shingle_size: usize) -> Self {
+        self.unit = unit;
+        self.shingle_size = shingle_size;
+        self
+    }
+
+    /// Compares the texts after normalizing them.
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// Sets the signature length. It is split into bands so that pairs with a
+    /// similarity around the threshold are likely to share a band.
+    pub fn with_num_hashes(mut self, num_hashes: usize) -> Self {
+        let (bands, rows) = bands_for_threshold(self.threshold, num_hashes);
+        self.bands = bands;
+        self.rows = rows;
+        self.index = Mutex::new(MinHashIndex {
+            buckets: vec![HashMap::new(); bands],
+            signatures: Vec::new(),
+        });
+        self
+    }
+
+    /// Groups the removed pairs by the kept pair they are similar to, see
+    /// [`crate::deduplicator::ParallelDeduplicator::with_cluster_report`].
+    /// Kept pairs are not held in memory, so a group is named after the
+    /// first of its removed pairs that is tracked.
+    pub fn with_cluster_report(mut self, clusters: usize, examples: usize) -> Self {
+        self.clusters = ClusterTracker::new(clusters, examples);
+        self
+    }
+
+    pub fn reports_clusters(&self) -> bool {
+        self.clusters.is_some()
+    }
+
+    /// The largest groups of near duplicates found so far, by number of pairs.
+    pub fn largest_clusters(&self) -> Vec<DuplicateCluster> {
+        self.clusters.as_ref().map(|x| x.largest()).unwrap_or_default()
+    }
+
+    fn shingles(&self, text: &str, seed: u8, hashes: &mut Vec<u64>) {
+        let text = self.normalization.apply(text);
+        let tokens: Vec<&str> = match self.unit {
+            LengthFilterUnit::Char => text.graphemes(true).collect(),
+            LengthFilterUnit::Word => text.split_whitespace().collect(),
+        };
+        let size = self.shingle_size.min(tokens.len()).max(1);
+        for shingle in tokens.windows(size) {
+            let mut hasher = DefaultHasher::new();
+            seed.hash(&mut hasher);
+            shingle.hash(&mut hasher);
+            hashes.push(hasher.finish());
+        }
+    }
+
+    /// Returns None for pairs without shingles, whose signatures would all be
+    /// equal.
+    fn signature(&self, bitext: &BiText) -> Option<Vec<u32>> {
+        let mut shingles = Vec::new();
+        let translation = bitext.translation.as_deref().unwrap_or_default();
+        match self.side {
+            KeySide::Source => self.shingles(&bitext.text, 0, &mut shingles),
+            KeySide::Target => self.shingles(translation, 1, &mut shingles),
+            KeySide::Pair => {
+                self.shingles(&bitext.text, 0, &mut shingles);
+                self.shingles(translation, 1, &mut shingles);
+            }
+        }
+        if shingles.is_empty() {
+            return None;
+        }
+        let signature = (0..self.bands * self.rows)
+            .map(|i| {
+                let seed = mix(i as u64);
+                shingles.iter().map(|x| mix(x ^ seed) as u32).min().unwrap_or(u32::MAX)
+            })
+            .collect();
+        Some(signature)
+    }
+
+    fn band_hash(&self, signature: &[u32], band: usize) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        signature[band * self.rows..(band + 1) * self.rows].hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Deduplicates one chunk of a corpus against itself and all earlier chunks.
+    /// The removed pairs are returned with the estimated similarity. Pairs
+    /// whose compared side is empty are kept, they are left to exact
+    /// deduplication.
+    pub fn deduplicate_chunk(&self, bitext: Vec<BiText>) -> (Vec<BiText>, Vec<Rejection>) {
+        let signatures: Vec<Option<Vec<u32>>> = bitext.par_iter().map(|x| self.signature(x)).collect();
+        let mut index = self.index.lock().unwrap();
+        let mut kept = Vec::with_capacity(bitext.len());
+        let mut rejected = Vec::new();
+        for (bitext, signature) in bitext.into_iter().zip(signatures) {
+            let Some(signature) = signature else {
+                kept.push(bitext);
+                continue;
+            };
+            let band_hashes: Vec<u64> = (0..self.bands).map(|band| self.band_hash(&signature, band)).collect();
+            let similar = band_hashes
+                .iter()
+                .enumerate()
+                .filter_map(|(band, hash)| index.buckets[band].get(hash))
+                .flatten()
+                .find_map(|candidate| {
+                    let similarity = estimate_jaccard(&signature, &index.signatures[*candidate]);
+                    (similarity >= self.threshold).then_some((*candidate, similarity))
+                });
+            match similar {
+                Some((candidate, similarity)) => {
+                    if let Some(clusters) = &self.clusters {
+                        clusters.add(candidate as u64, || self.side.readable_key(&bitext, &self.normalization), &bitext);
+                    }
+                    rejected.push(Rejection {
+                        bitext,
+                        filter: "near_deduplicator",
+                        reason: format!("similarity {:.2} to an earlier pair", similarity),
+                    })
+                }
+                None => {
+                    let id = index.signatures.len();
+                    for (band, hash) in band_hashes.into_iter().enumerate() {
+                        index.buckets[band].entry(hash).or_default().push(id);
+                    }
+                    index.signatures.push(signature);
+                    kept.push(bitext);
+                }
+            }
+        }
+        (kept, rejected)
+    }
+}
+
+/// Splits `num_hashes` into bands of rows such that the similarity at which a
+/// pair becomes a candidate with the most likely band, `(1 / bands) ^ (1 / rows)`,
+/// is as close to `threshold` as possible without exceeding it.
+fn bands_for_threshold(threshold: f64, num_hashes: usize) -> (usize, usize) {
+    (1..=num_hashes.max(1))
+        .map(|bands| (bands, num_hashes.max(1) / bands))
+        .filter(|(bands, rows)| (1.0 / *bands as f64).powf(1.0 / *rows as f64) <= threshold)
+        .max_by(|(a, x), (b, y)| {
+            let a = (1.0 / *a as f64).powf(1.0 / *x as f64);
+            let b = (1.0 / *b as f64).powf(1.0 / *y as f64);
+            a.total_cmp(&b)
+        })
+        .unwrap_or((num_hashes.max(1), 1))
+}
+
+fn estimate_jaccard(a: &[u32], b: &[u32]) -> f64 {
+    let equal = a.iter().zip(b.iter()).filter(|(x, y)| x == y).count();
+    equal as f64 / a.len().max(1) as f64
+}
+
+/// SplitMix64, to derive the hash functions from one shingle hash.
+fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitexts(texts: &[&str]) -> Vec<BiText> {
+        texts
+            .iter()
+            .map(|x| BiText::new(String::from(*x), None, Some(String::from("Die gleiche Übersetzung für alle Seiten hier")), None))
+            .collect()
+    }
+
+    #[test]
+    fn test_near_deduplicator() {
+        let deduplicator = NearDeduplicator::new(0.5).with_side(KeySide::Source);
+        let (kept, rejected) = deduplicator.deduplicate_chunk(bitexts(&[
+            "Free shipping on all orders of the new blue running shoes model 2021 until the end of the month",
+            "Free shipping on all orders of the new blue running shoes model 2022 until the end of the month",
+            "The committee approved the budget for the next fiscal year after a long debate",
+        ]));
+        assert_eq!(kept.len(), 2);
+        assert_eq!(rejected[0].filter, "near_deduplicator");
+        assert!(rejected[0].bitext.text.contains("2022"));
+
+        let (kept, _) = deduplicator.deduplicate_chunk(bitexts(&[
+            "Free shipping on all orders of the new red running shoes model 2021 until the end of the month",
+        ]));
+        assert_eq!(kept.len(), 0);
+    }
+
+    #[test]
+    fn test_near_deduplicator_sides() {
+        let texts = ["Completely different source one", "Another unrelated source sentence"];
+        let (kept, _) = NearDeduplicator::new(0.8).with_side(KeySide::Target).deduplicate_chunk(bitexts(&texts));
+        assert_eq!(kept.len(), 1);
+        let (kept, _) = NearDeduplicator::new(0.8).with_side(KeySide::Pair).deduplicate_chunk(bitexts(&texts));
+        assert_eq!(kept.len(), 2);
+    }
+
+    #[test]
+    fn test_near_deduplicator_short_pairs() {
+        let deduplicator = NearDeduplicator::new(0.8).with_side(KeySide::Source);
+        let (kept, _) = deduplicator.deduplicate_chunk(bitexts(&["", "  ", "Yes", "No"]));
+        assert_eq!(kept.len(), 4);
+        let pairs = vec![
+            BiText::new(String::new(), None, Some(String::new()), None),
+            BiText::new(String::from("Hi"), None, None, None),
+            BiText::new(String::new(), None, Some(String::from(" ")), None),
+        ];
+        let (kept, _) = NearDeduplicator::new(0.8).deduplicate_chunk(pairs);
+        assert_eq!(kept.len(), 3);
+    }
+
+    #[test]
+    fn test_near_deduplicator_clusters() {
+        let deduplicator = NearDeduplicator::new(0.5).with_side(KeySide::Source).with_cluster_report(1, 1);
+        let mut bitexts = bitexts(&[
+            "Free shipping on all orders of the new blue running shoes model 2021 until the end of the month",
+            "The committee approved the budget for the next fiscal year after a long debate",
+            "Free shipping on all orders of the new blue running shoes model 2022 until the end of the month",
+            "Free shipping on all orders of the new blue running shoes model 2023 until the end of the month",
+        ]);
+        for (line, bitext) in bitexts.iter_mut().enumerate() {
+            bitext.line = Some(line as u64 + 1);
+        }
+        deduplicator.deduplicate_chunk(bitexts);
+        let clusters = deduplicator.largest_clusters();
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].count, 3);
+        assert!(clusters[0].key.contains("2022"));
+        assert_eq!(clusters[0].examples.iter().map(|x| x.line).collect::<Vec<_>>(), vec![Some(3)]);
+    }
+
+    #[test]
+    fn test_bands_for_threshold() {
+        assert_eq!(bands_for_threshold(0.8, 128), (12, 10));
+        let (bands, rows) = bands_for_threshold(0.5, 128);
+        assert!(bands * rows <= 128);
+        assert!((1.0 / bands as f64).powf(1.0 / rows as f64) <= 0.5);
+    }
+}
@@ -1,6 +1,8 @@
+use std::collections::BTreeMap;
 use pyo3::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 #[pyclass]
 pub struct BiText {
     #[pyo3(get, set)]
@@ -11,6 +13,23 @@ pub struct BiText {
     pub translation: Option<String>,
     #[pyo3(get, set)]
     pub translation_language: Option<String>,
+    /// Additional information from the input format that should survive
+    /// cleaning, e.g. TMX properties.
+    #[pyo3(get, set)]
+    pub metadata: BTreeMap<String, String>,
+    /// Scores of the filters that ran in score mode, by filter name.
+    #[pyo3(get, set)]
+    pub scores: BTreeMap<String, f64>,
+    /// Line of the pair in the input, counted from 1, or the number of its
+    /// translation unit in TMX. Pipelines number pairs without one by their
+    /// position in the pipeline input.
+    #[pyo3(get, set)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u64>,
+    /// Source and target as read, before any step changed them. Only kept by
+    /// pipelines with steps that report removed pairs, see [`BiText::keep_original`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<Box<(String, Option<String>)>>,
 }
 
 impl BiText {
@@ -25,6 +44,26 @@ impl BiText {
             language,
             translation,
             translation_language,
+            metadata: BTreeMap::new(),
+            scores: BTreeMap::new(),
+            line: None,
+            original: None,
+        }
+    }
+
+    /// Remembers the current texts as the original ones, unless an earlier
+    /// pipeline already did.
+    pub fn keep_original(&mut self) {
+        if self.original.is_none() {
+            self.original = Some(Box::new((self.text.clone(), self.translation.clone())));
+        }
+    }
+
+    /// The source and target as read, see [`BiText::original`].
+    pub fn original_text(&self) -> (&str, Option<&str>) {
+        match &self.original {
+            Some(original) => (&original.0, original.1.as_deref()),
+            None => (&self.text, self.translation.as_deref()),
         }
     }
 }
@@ -43,6 +82,10 @@ impl BiText {
             language,
             translation,
             translation_language,
+            metadata: BTreeMap::new(),
+            scores: BTreeMap::new(),
+            line: None,
+            original: None,
         }
     }
 }
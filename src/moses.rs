@@ -1,10 +1,317 @@
-use std::fs;
-use std::iter::zip;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+use crate::compression;
 use crate::model::BiText;
 
-pub fn align_moses(file_src: &str, file_trg: &str, src_lang: Option<String>, trg_lang: Option<String>) -> Vec<BiText>{
-    zip(
-        fs::read_to_string(file_src).expect("Src file invalid").split("\n"),
-        fs::read_to_string(file_trg).expect("Trg file invalid").split("\n")
-    ).into_iter().map(|(x, y)| BiText::new(x.parse().unwrap(), src_lang.clone(), Some(y.parse().unwrap()), trg_lang.clone())).collect()
-}
\ No newline at end of file
+/// The side of a parallel corpus a file belongs to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CorpusSide {
+    Source,
+    Target,
+}
+
+impl fmt::Display for CorpusSide {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorpusSide::Source => write!(f, "source"),
+            CorpusSide::Target => write!(f, "target"),
+        }
+    }
+}
+
+/// What to do when the source and target file have a different number of lines.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum MismatchPolicy {
+    /// Stop with a [`MosesError::LineCountMismatch`].
+    #[default]
+    Error,
+    /// Silently drop the lines of the longer file.
+    Truncate,
+    /// Keep reading the longer file and pair it with empty segments.
+    Pad,
+}
+
+impl FromStr for MismatchPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "error" => Ok(MismatchPolicy::Error),
+            "truncate" => Ok(MismatchPolicy::Truncate),
+            "pad" => Ok(MismatchPolicy::Pad),
+            _ => Err(format!("Unknown mismatch policy '{}', expected error, truncate or pad", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MosesError {
+    Io { side: CorpusSide, source: io::Error },
+    InvalidUtf8 { side: CorpusSide, line: usize },
+    LineCountMismatch { src_lines: usize, trg_lines: usize, exhausted: CorpusSide },
+}
+
+impl fmt::Display for MosesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MosesError::Io { side, source } => write!(f, "Could not read {} file: {}", side, source),
+            MosesError::InvalidUtf8 { side, line } => write!(f, "Invalid UTF-8 in {} file on line {}", side, line),
+            MosesError::LineCountMismatch { src_lines, trg_lines, exhausted } => write!(
+                f,
+                "Source file has {} lines but target file has {} lines, the {} file ran out first",
+                src_lines, trg_lines, exhausted
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MosesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MosesError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Reads one side of a Moses corpus line by line, keeping track of the line number.
+struct LineReader<R: BufRead> {
+    reader: R,
+    side: CorpusSide,
+    line: usize,
+    buffer: Vec<u8>,
+}
+
+impl<R: BufRead> LineReader<R> {
+    fn new(reader: R, side: CorpusSide) -> Self {
+        LineReader { reader, side, line: 0, buffer: Vec::new() }
+    }
+
+    fn read_raw_line(&mut self) -> Result<bool, MosesError> {
+        self.buffer.clear();
+        let read = self.reader
+            .read_until(b'\n', &mut self.buffer)
+            .map_err(|source| MosesError::Io { side: self.side, source })?;
+        if read == 0 {
+            return Ok(false);
+        }
+        self.line += 1;
+        if self.buffer.ends_with(b"\n") {
+            self.buffer.pop();
+            if self.buffer.ends_with(b"\r") {
+                self.buffer.pop();
+            }
+        }
+        Ok(true)
+    }
+
+    fn next_line(&mut self) -> Result<Option<String>, MosesError> {
+        if !self.read_raw_line()? {
+            return Ok(None);
+        }
+        match String::from_utf8(std::mem::take(&mut self.buffer)) {
+            Ok(line) => Ok(Some(line)),
+            Err(_) => Err(MosesError::InvalidUtf8 { side: self.side, line: self.line }),
+        }
+    }
+
+    /// Reads the rest of the file and returns the total number of lines.
+    fn count_lines(&mut self) -> Result<usize, MosesError> {
+        while self.read_raw_line()? {}
+        Ok(self.line)
+    }
+}
+
+/// Lazily pairs up the lines of a Moses style source and target file.
+///
+/// Both files are read line by line through a buffered reader, so only the
+/// pairs that are currently being processed are kept in memory.
+pub struct MosesReader<S: BufRead, T: BufRead> {
+    src: LineReader<S>,
+    trg: LineReader<T>,
+    src_lang: Option<String>,
+    trg_lang: Option<String>,
+    policy: MismatchPolicy,
+    done: bool,
+}
+
+impl MosesReader<Box<dyn BufRead>, Box<dyn BufRead>> {
+    /// Opens both files, decompressing them if necessary.
+    pub fn open(file_src: &str, file_trg: &str, src_lang: Option<String>, trg_lang: Option<String>) -> Result<Self, MosesError> {
+        let src = compression::open(file_src).map_err(|source| MosesError::Io { side: CorpusSide::Source, source })?;
+        let trg = compression::open(file_trg).map_err(|source| MosesError::Io { side: CorpusSide::Target, source })?;
+        Ok(MosesReader::new(src, trg, src_lang, trg_lang))
+    }
+}
+
+impl<S: BufRead, T: BufRead> MosesReader<S, T> {
+    pub fn new(src: S, trg: T, src_lang: Option<String>, trg_lang: Option<String>) -> Self {
+        MosesReader {
+            src: LineReader::new(src, CorpusSide::Source),
+            trg: LineReader::new(trg, CorpusSide::Target),
+            src_lang,
+            trg_lang,
+            policy: MismatchPolicy::default(),
+            done: false,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: MismatchPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    fn next_pair(&mut self) -> Result<Option<(String, String)>, MosesError> {
+        let src = self.src.next_line()?;
+        let trg = self.trg.next_line()?;
+        match (src, trg) {
+            (Some(src), Some(trg)) => Ok(Some((src, trg))),
+            (None, None) => Ok(None),
+            (src, trg) => {
+                let exhausted = if src.is_none() { CorpusSide::Source } else { CorpusSide::Target };
+                match self.policy {
+                    MismatchPolicy::Truncate => Ok(None),
+                    MismatchPolicy::Pad => Ok(Some((src.unwrap_or_default(), trg.unwrap_or_default()))),
+                    MismatchPolicy::Error => Err(MosesError::LineCountMismatch {
+                        src_lines: self.src.count_lines()?,
+                        trg_lines: self.trg.count_lines()?,
+                        exhausted,
+                    }),
+                }
+            }
+        }
+    }
+}
+
+impl<S: BufRead, T: BufRead> Iterator for MosesReader<S, T> {
+    type Item = Result<BiText, MosesError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.next_pair() {
+            Ok(Some((src, trg))) => {
+                let mut bitext = BiText::new(src, self.src_lang.clone(), Some(trg), self.trg_lang.clone());
+                bitext.line = Some(self.src.line.max(self.trg.line) as u64);
+                Some(Ok(bitext))
+            }
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+/// Writes bitexts as a source and a target file with one segment per line.
+///
+/// Line breaks inside a segment are replaced by a space to keep both files aligned.
+pub struct MosesWriter<S: Write, T: Write> {
+    src: S,
+    trg: T,
+}
+
+impl<S: Write, T: Write> MosesWriter<S, T> {
+    pub fn new(src: S, trg: T) -> Self {
+        MosesWriter { src, trg }
+    }
+
+    pub fn write(&mut self, bitext: &BiText) -> io::Result<()> {
+        writeln!(self.src, "{}", bitext.text.replace(['\n', '\r'], " "))?;
+        writeln!(self.trg, "{}", bitext.translation.as_deref().unwrap_or_default().replace(['\n', '\r'], " "))
+    }
+
+    pub fn finish(mut self) -> io::Result<(S, T)> {
+        self.src.flush()?;
+        self.trg.flush()?;
+        Ok((self.src, self.trg))
+    }
+}
+
+pub fn align_moses(file_src: &str, file_trg: &str, src_lang: Option<String>, trg_lang: Option<String>) -> Result<Vec<BiText>, MosesError>{
+    MosesReader::open(file_src, file_trg, src_lang, trg_lang)?.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_moses_reader() {
+        let src = "Hello\nWorld\n".as_bytes();
+        let trg = "Hallo\nWelt\n".as_bytes();
+        let bitexts: Vec<BiText> = MosesReader::new(src, trg, Some(String::from("en")), Some(String::from("de")))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected = vec![
+            BiText {
+                line: Some(1),
+                ..BiText::new(String::from("Hello"), Some(String::from("en")), Some(String::from("Hallo")), Some(String::from("de")))
+            },
+            BiText {
+                line: Some(2),
+                ..BiText::new(String::from("World"), Some(String::from("en")), Some(String::from("Welt")), Some(String::from("de")))
+            },
+        ];
+        assert_eq!(bitexts, expected);
+    }
+
+    #[test]
+    fn test_moses_reader_mismatch_error() {
+        let src = "a\nb\n".as_bytes();
+        let trg = "a\nb\nc\nd\n".as_bytes();
+        let result: Result<Vec<BiText>, MosesError> = MosesReader::new(src, trg, None, None).collect();
+        match result {
+            Err(MosesError::LineCountMismatch { src_lines, trg_lines, exhausted }) => {
+                assert_eq!((src_lines, trg_lines, exhausted), (2, 4, CorpusSide::Source));
+            }
+            _ => panic!("Expected a line count mismatch"),
+        }
+    }
+
+    #[test]
+    fn test_moses_reader_mismatch_policies() {
+        let truncated: Vec<BiText> = MosesReader::new("a\nb\nc".as_bytes(), "a\n".as_bytes(), None, None)
+            .with_policy(MismatchPolicy::Truncate)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(truncated.len(), 1);
+        let padded: Vec<BiText> = MosesReader::new("a\nb\nc".as_bytes(), "a\n".as_bytes(), None, None)
+            .with_policy(MismatchPolicy::Pad)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(padded.len(), 3);
+        assert_eq!(padded[2].translation, Some(String::from("")));
+    }
+
+    #[test]
+    fn test_moses_writer() {
+        let bitexts = vec![
+            BiText::new(String::from("Hello\nthere"), None, Some(String::from("Hallo")), None),
+            BiText::new(String::from("World"), None, None, None),
+        ];
+        let mut writer = MosesWriter::new(Vec::new(), Vec::new());
+        bitexts.iter().for_each(|x| writer.write(x).unwrap());
+        let (src, trg) = writer.finish().unwrap();
+        assert_eq!(String::from_utf8(src).unwrap(), "Hello there\nWorld\n");
+        assert_eq!(String::from_utf8(trg).unwrap(), "Hallo\n\n");
+    }
+
+    #[test]
+    fn test_moses_reader_invalid_utf8() {
+        let src: &[u8] = b"a\n\xff\xfe\n";
+        let trg = "a\nb\n".as_bytes();
+        let result: Result<Vec<BiText>, MosesError> = MosesReader::new(src, trg, None, None).collect();
+        match result {
+            Err(MosesError::InvalidUtf8 { side, line }) => assert_eq!((side, line), (CorpusSide::Source, 2)),
+            _ => panic!("Expected invalid UTF-8"),
+        }
+    }
+}
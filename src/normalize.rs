@@ -0,0 +1,60 @@
+use std::sync::OnceLock;
+use regex::Regex;
+use serde::Deserialize;
+
+static PUNCTUATION: OnceLock<Regex> = OnceLock::new();
+
+/// Optional normalizations applied before texts are compared, e.g. to build
+/// deduplication keys under which "Page 1 of 10" and "Page 2 of 10" are equal.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Normalization {
+    pub lowercase: bool,
+    /// Removes all Unicode punctuation.
+    pub punctuation: bool,
+    /// Replaces every digit by `0`, so that only the shape of numbers is compared.
+    pub digits: bool,
+    /// Removes all whitespace.
+    pub whitespace: bool,
+    pub diacritics: bool,
+}
+
+impl Normalization {
+    pub fn apply(&self, text: &str) -> String {
+        let mut text = match self.diacritics {
+            true => diacritics::remove_diacritics(text),
+            false => String::from(text),
+        };
+        if self.lowercase {
+            text = text.to_lowercase();
+        }
+        if self.punctuation {
+            let punctuation = PUNCTUATION.get_or_init(|| Regex::new(r"\p{P}").unwrap());
+            text = punctuation.replace_all(&text, "").into_owned();
+        }
+        if self.digits {
+            text = text.chars().map(|x| if x.is_numeric() { '0' } else { x }).collect();
+        }
+        if self.whitespace {
+            text.retain(|x| !x.is_whitespace());
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalization() {
+        let text = "Hello, World! ¿Qué? 1.234";
+        assert_eq!(Normalization::default().apply(text), text);
+        let normalization = Normalization { lowercase: true, punctuation: true, digits: true, ..Normalization::default() };
+        assert_eq!(normalization.apply(text), "hello world qué 0000");
+
+        let normalization = Normalization { lowercase: true, digits: true, whitespace: true, diacritics: true, ..Normalization::default() };
+        assert_eq!(normalization.apply("Page 1 of 10"), normalization.apply("page 2 of 10"));
+        assert_eq!(normalization.apply("Qué  tal 2"), "quetal0");
+    }
+}
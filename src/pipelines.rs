@@ -1,23 +1,260 @@
-use crate::{filter, cleaner, deduplicator};
-use crate::deduplicator::{Deduplicator, TargetDeduplicator};
-use crate::filter::{Filter, LengthFilterUnit};
+use std::collections::HashSet;
+use std::io;
+use std::time::Instant;
+use rayon::prelude::*;
+use crate::configparser::*;
+use crate::filter::Rejection;
 use crate::model::BiText;
+use crate::stats;
+use crate::stats::{PipelineReport, StepStats};
+use crate::step::Step;
 
+/// The pipeline used when no config is given.
+pub(crate) const DEFAULT_PIPELINE: &str = "steps:
+  - whitespace_cleaner
+  - source_deduplicator
+  - length_filter: {min: 5, max: 40, unit: word}
+  - length_ratio_filter: {threshold: 0.8, unit: word}
+  - long_word_filter: {threshold: 30}
+  - diacritics_cleaner
+";
 
-pub(crate) fn default_pipeline(bitext: Vec<BiText>) -> Vec<BiText> {
-    let mut bitext = cleaner::whitespace_cleaner(bitext);
-    bitext = TargetDeduplicator::deduplicate(bitext, false);
-    bitext = filter::LengthFilter::new(5,40, LengthFilterUnit::Word).filter_text(bitext);
-    println!("lengthfilter: {}", bitext.len());
-    // bitext = filter::LangIdFilter::new(String::from("German")).filter_text(bitext);
-    //bitext = filter::LangIdFilter::new(String::from("English")).filter_text(bitext);
-    println!("langid {}", bitext.len());
-    bitext = filter::LengthRatioFilter::new(0.8, LengthFilterUnit::Word).filter_text(bitext);
-    println!("lengthratiofilter {}", bitext.len());
-    bitext = filter::LongWordFilter::new(30).filter_text(bitext);
-    println!("longword {}", bitext.len());
-    //bitext = filter::SimilarityFilter::new(2, true).filter_text(bitext);
-    println!("similarity {}", bitext.len());
-    bitext = cleaner::diacritics_cleaner(bitext);
-    return bitext
-}
\ No newline at end of file
+/// Splits a stream of bitexts into vectors of at most `chunk_size` pairs, so
+/// each chunk can be processed in parallel while memory stays bounded.
+pub(crate) fn chunked<I: Iterator<Item=BiText>>(bitexts: I, chunk_size: usize) -> impl Iterator<Item=Vec<BiText>> {
+    let mut bitexts = bitexts.peekable();
+    std::iter::from_fn(move || {
+        bitexts.peek()?;
+        Some(bitexts.by_ref().take(chunk_size.max(1)).collect())
+    })
+}
+
+/// The steps of a pipeline, built once and then run on every chunk.
+///
+/// Statistics are collected over all chunks and available from [`Pipeline::report`].
+pub(crate) struct Pipeline {
+    steps: Vec<(Box<dyn Step>, StepStats)>,
+    pairs_in: usize,
+    pairs_out: usize,
+    seconds: f64,
+    /// The steps before this one returned all pairs they held back.
+    finished: usize,
+    /// A step reports removed pairs as they were read, after a step that modifies pairs.
+    keep_originals: bool,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline { steps: Vec::new(), pairs_in: 0, pairs_out: 0, seconds: 0.0, finished: 0, keep_originals: false }
+    }
+
+    pub fn from_config(config: &PipelineConfig) -> Result<Self, ConfigError> {
+        let mut pipeline = Pipeline::new();
+        let mut score_names = HashSet::new();
+        for step in config.steps()?.iter() {
+            if let Some(score_name) = step.score_name()? {
+                if !score_names.insert(score_name.clone()) {
+                    return Err(step.error(format!("an earlier step already writes the score '{}', set another score_name", score_name)));
+                }
+            }
+            pipeline.add_step(build_step(step)?);
+        }
+        Ok(pipeline)
+    }
+
+    pub fn add_step(&mut self, step: Box<dyn Step>) {
+        self.keep_originals |= step.keeps_examples() && self.steps.iter().any(|(x, _)| x.modifies());
+        let stats = StepStats::new(step.name());
+        self.steps.push((step, stats));
+    }
+
+    /// Pairs without a line number are numbered by their position in the
+    /// input of the pipeline.
+    pub fn run(&mut self, mut bitext: Vec<BiText>, rejects: &mut Vec<Rejection>) -> io::Result<Vec<BiText>> {
+        let start = Instant::now();
+        let first_line = self.pairs_in as u64 + 1;
+        let keep_originals = self.keep_originals;
+        bitext.par_iter_mut().enumerate().for_each(|(index, x)| {
+            x.line.get_or_insert(first_line + index as u64);
+            if keep_originals {
+                x.keep_original();
+            }
+        });
+        self.pairs_in += bitext.len();
+        let bitext = self.run_steps(0, bitext, rejects)?;
+        self.pairs_out += bitext.len();
+        self.seconds += start.elapsed().as_secs_f64();
+        Ok(bitext)
+    }
+
+    /// Returns the next batch of pairs that steps held back until the end of
+    /// the corpus, after running them through the steps that follow. Called
+    /// after the last chunk until it returns None.
+    pub fn finish(&mut self, rejects: &mut Vec<Rejection>) -> io::Result<Option<Vec<BiText>>> {
+        let start = Instant::now();
+        while self.finished < self.steps.len() {
+            let index = self.finished;
+            let (step, stats) = &mut self.steps[index];
+            let step_start = Instant::now();
+            let held_back = step.finish(rejects)?;
+            stats.seconds += step_start.elapsed().as_secs_f64();
+            let Some(held_back) = held_back else {
+                self.finished += 1;
+                continue;
+            };
+            stats.pairs_out += held_back.len();
+            stats.chars_removed -= stats::char_count(&held_back) as i64;
+            let bitext = self.run_steps(index + 1, held_back, rejects)?;
+            self.pairs_out += bitext.len();
+            self.seconds += start.elapsed().as_secs_f64();
+            return Ok(Some(bitext));
+        }
+        self.finished = 0;
+        self.seconds += start.elapsed().as_secs_f64();
+        Ok(None)
+    }
+
+    fn run_steps(&mut self, from: usize, mut bitext: Vec<BiText>, rejects: &mut Vec<Rejection>) -> io::Result<Vec<BiText>> {
+        for (step, stats) in self.steps[from..].iter_mut() {
+            let step_start = Instant::now();
+            let pairs_in = bitext.len();
+            let chars_in = stats::char_count(&bitext);
+            let fingerprints = if step.modifies() { Some(stats::fingerprints(&bitext)) } else { None };
+
+            bitext = step.process(bitext, rejects)?;
+
+            // Steps either modify pairs one by one or remove pairs, so only
+            // a step that kept all pairs can have modified some of them.
+            if let Some(fingerprints) = fingerprints.filter(|x| x.len() == bitext.len()) {
+                stats.pairs_modified += stats::fingerprints(&bitext)
+                    .iter()
+                    .zip(fingerprints.iter())
+                    .filter(|(x, y)| x != y)
+                    .count();
+            }
+            stats.pairs_in += pairs_in;
+            stats.pairs_out += bitext.len();
+            stats.chars_removed += chars_in as i64 - stats::char_count(&bitext) as i64;
+            stats.seconds += step_start.elapsed().as_secs_f64();
+        }
+        Ok(bitext)
+    }
+
+    pub fn report(&self) -> PipelineReport {
+        PipelineReport {
+            pairs_in: self.pairs_in,
+            pairs_out: self.pairs_out,
+            seconds: self.seconds,
+            steps: self
+                .steps
+                .iter()
+                .map(|(step, stats)| StepStats { details: step.details(), clusters: step.clusters(), ..stats.clone() })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{LengthFilter, LengthFilterUnit};
+
+    #[test]
+    fn test_default_pipeline() {
+        let config = PipelineConfig::from_str(DEFAULT_PIPELINE).unwrap();
+        let mut pipeline = Pipeline::from_config(&config).unwrap();
+        let bitexts = vec!["a  b c d e f", "a b c d e f", "a b"]
+            .into_iter()
+            .map(|x| BiText::new(String::from(x), None, Some(String::from("a b c d e f")), None))
+            .collect();
+        let mut rejects = Vec::new();
+        let cleaned = pipeline.run(bitexts, &mut rejects).unwrap();
+        assert_eq!(cleaned.len(), 1);
+        assert_eq!(rejects.len(), 2);
+
+        let report = pipeline.report();
+        assert_eq!((report.pairs_in, report.pairs_out), (3, 1));
+        assert_eq!(report.steps[0].name, "whitespace_cleaner");
+        assert_eq!(report.steps[0].pairs_modified, 1);
+        assert_eq!(report.steps[0].chars_removed, 1);
+        assert_eq!((report.steps[1].pairs_in, report.steps[1].pairs_out), (3, 2));
+        assert_eq!(report.steps[1].chars_removed, 22);
+    }
+
+    #[test]
+    fn test_invalid_pipeline() {
+        let config = PipelineConfig::from_str("steps:\n  - length_filter: {min: 5, max: 4, unit: word}\n").unwrap();
+        let error = Pipeline::from_config(&config).err().unwrap().to_string();
+        assert_eq!(error, "Invalid config: step 1 (length_filter): min (5) is larger than max (4)");
+        let config = PipelineConfig::from_str("steps:\n  - whitespace_cleaner\n  - lenght_filter\n").unwrap();
+        let error = Pipeline::from_config(&config).err().unwrap().to_string();
+        assert_eq!(error, "Invalid config: step 2 (lenght_filter): unknown step 'lenght_filter'");
+        let config = PipelineConfig::from_str(
+            "steps:\n  - regexp_filter: {regexp: 'x', mode: score}\n  - regexp_filter: {regexp: 'y', mode: score}\n",
+        )
+        .unwrap();
+        let error = Pipeline::from_config(&config).err().unwrap().to_string();
+        assert_eq!(error, "Invalid config: step 2 (regexp_filter): an earlier step already writes the score 'regexp_filter', set another score_name");
+        let config = PipelineConfig::from_str("steps:\n  - pair_deduplicator: {keep: last}\n").unwrap();
+        assert!(Pipeline::from_config(&config).is_err());
+        let config = PipelineConfig::from_str("steps:\n  - pair_deduplicator: {store: disk, partitions: 100000}\n").unwrap();
+        let error = Pipeline::from_config(&config).err().unwrap().to_string();
+        assert_eq!(error, "Invalid config: step 1 (pair_deduplicator): partitions must be between 1 and 512, got 100000");
+    }
+
+    #[test]
+    fn test_pipeline_finish() {
+        let config = PipelineConfig::from_str(
+            "steps:\n  - source_deduplicator: {keep: longest, store: disk}\n  - length_filter: {min: 1, max: 2, unit: word}\n",
+        )
+        .unwrap();
+        let mut pipeline = Pipeline::from_config(&config).unwrap();
+        let bitexts = vec![("a", "b"), ("a", "b c"), ("c", "d e f")]
+            .into_iter()
+            .map(|(src, trg)| BiText::new(String::from(src), None, Some(String::from(trg)), None))
+            .collect();
+        let mut rejects = Vec::new();
+        assert_eq!(pipeline.run(bitexts, &mut rejects).unwrap().len(), 0);
+        let cleaned = pipeline.finish(&mut rejects).unwrap().unwrap();
+        assert_eq!(pipeline.finish(&mut rejects).unwrap(), None);
+        assert_eq!(cleaned.len(), 1);
+        assert_eq!(cleaned[0].translation.as_deref(), Some("b c"));
+        assert_eq!(rejects.len(), 2);
+
+        let report = pipeline.report();
+        assert_eq!((report.pairs_in, report.pairs_out), (3, 1));
+        assert_eq!((report.steps[0].pairs_in, report.steps[0].pairs_out), (3, 2));
+        assert_eq!(report.steps[0].chars_removed, 2);
+    }
+
+    #[test]
+    fn test_dynamic_pipeline() {
+        let mut pipeline = Pipeline::new();
+        pipeline.add_step(build_step(&StepConfig::new("whitespace_cleaner", serde_yaml::Value::Null)).unwrap());
+        pipeline.add_step(Box::new(LengthFilter::new(2, 3, LengthFilterUnit::Word)));
+        let bitexts = vec!["a  b", "a b c d"]
+            .into_iter()
+            .map(|x| BiText::new(String::from(x), None, None, None))
+            .collect();
+        let cleaned = pipeline.run(bitexts, &mut Vec::new()).unwrap();
+        assert_eq!(cleaned, vec![BiText { line: Some(1), ..BiText::new(String::from("a b"), None, None, None) }]);
+    }
+
+    #[test]
+    fn test_pipeline_cluster_examples() {
+        let config = PipelineConfig::from_str(
+            "steps:\n  - whitespace_cleaner\n  - source_deduplicator: {clusters: 1, cluster_examples: 2}\n",
+        )
+        .unwrap();
+        let mut pipeline = Pipeline::from_config(&config).unwrap();
+        let chunks = [vec!["a b", "c"], vec!["a  b", "a   b"]];
+        for chunk in chunks {
+            let bitexts = chunk.into_iter().map(|x| BiText::new(String::from(x), None, None, None)).collect();
+            pipeline.run(bitexts, &mut Vec::new()).unwrap();
+        }
+        let report = pipeline.report();
+        let examples: Vec<(Option<u64>, &str)> =
+            report.steps[1].clusters[0].examples.iter().map(|x| (x.line, x.source.as_str())).collect();
+        assert_eq!(examples, vec![(Some(3), "a  b"), (Some(4), "a   b")]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use rayon::prelude::*;
+use serde::Serialize;
+use crate::model::BiText;
+
+/// What a single step did over the whole run.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct StepStats {
+    pub name: String,
+    pub pairs_in: usize,
+    pub pairs_out: usize,
+    pub pairs_modified: usize,
+    /// Characters of source and target that were removed, including those of dropped pairs.
+    pub chars_removed: i64,
+    pub seconds: f64,
+    /// Step specific numbers, e.g. the false positive rate of a deduplicator.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub details: BTreeMap<String, f64>,
+    /// The largest groups of duplicates, if the step is a deduplicator that reports them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub clusters: Vec<DuplicateCluster>,
+}
+
+/// A group of pairs with the same deduplication key, or near duplicates of
+/// the same pair, see [`crate::deduplicator::ParallelDeduplicator::with_cluster_report`].
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DuplicateCluster {
+    pub key: String,
+    /// Number of pairs with the key, including the one that was kept.
+    pub count: usize,
+    /// The first pairs that were removed.
+    pub examples: Vec<DuplicateExample>,
+}
+
+/// A removed pair as it was read, before any step modified it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DuplicateExample {
+    /// Line of the pair in the input, see [`BiText::line`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<u64>,
+    pub source: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+impl DuplicateExample {
+    pub fn new(bitext: &BiText) -> Self {
+        let (source, target) = bitext.original_text();
+        DuplicateExample { line: bitext.line, source: String::from(source), target: target.map(String::from) }
+    }
+}
+
+impl StepStats {
+    pub fn new(name: &str) -> Self {
+        StepStats { name: String::from(name), ..StepStats::default() }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PipelineReport {
+    pub pairs_in: usize,
+    pub pairs_out: usize,
+    pub seconds: f64,
+    pub steps: Vec<StepStats>,
+}
+
+impl PipelineReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+/// Number of characters in the source and target of all pairs.
+pub fn char_count(bitext: &[BiText]) -> usize {
+    bitext
+        .par_iter()
+        .map(|x| x.text.chars().count() + x.translation.as_deref().unwrap_or_default().chars().count())
+        .sum()
+}
+
+/// A hash per pair, to find out which pairs a step changed.
+pub fn fingerprints(bitext: &[BiText]) -> Vec<u64> {
+    bitext
+        .par_iter()
+        .map(|x| {
+            let mut hasher = DefaultHasher::new();
+            x.text.hash(&mut hasher);
+            x.translation.hash(&mut hasher);
+            hasher.finish()
+        })
+        .collect()
+}
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+use std::io;
+use crate::cleaner::Cleaner;
+use crate::deduplicator::{ExternalDeduplicator, ParallelDeduplicator};
+use crate::filter::{Filter, Rejection, Score};
+use crate::langid::LangIdAnnotator;
+use crate::minhash::NearDeduplicator;
+use crate::model::BiText;
+use crate::stats::DuplicateCluster;
+use rayon::prelude::*;
+
+/// A single step of a pipeline.
+///
+/// The same step processes every chunk of a corpus, so steps that keep state
+/// between chunks, like deduplicators, hold it behind a lock.
+pub trait Step: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Processes one chunk and adds the pairs that were removed with a reason
+    /// to `rejects`. Only steps that spill pairs to disk can fail.
+    fn process(&self, bitext: Vec<BiText>, rejects: &mut Vec<Rejection>) -> io::Result<Vec<BiText>>;
+
+    /// Whether the step changes the text of pairs, and not only removes pairs.
+    fn modifies(&self) -> bool {
+        false
+    }
+
+    /// Whether the step can run as a [`ScoringStep`].
+    fn can_score(&self) -> bool {
+        false
+    }
+
+    /// See [`Filter::score`].
+    fn score(&self, _bitext: &BiText) -> Option<Score> {
+        None
+    }
+
+    /// Called after the last chunk, for steps that hold pairs back until they
+    /// have seen the whole corpus. Returns these pairs in batches, and is
+    /// called again until it returns None.
+    fn finish(&self, _rejects: &mut Vec<Rejection>) -> io::Result<Option<Vec<BiText>>> {
+        Ok(None)
+    }
+
+    /// Step specific numbers for the statistics report.
+    fn details(&self) -> BTreeMap<String, f64> {
+        BTreeMap::new()
+    }
+
+    /// The largest groups of duplicates a deduplicator found, for the statistics report.
+    fn clusters(&self) -> Vec<DuplicateCluster> {
+        Vec::new()
+    }
+
+    /// Whether the step reports removed pairs as they were read, see
+    /// [`BiText::keep_original`].
+    fn keeps_examples(&self) -> bool {
+        false
+    }
+}
+
+impl<F: Filter> Step for F {
+    fn name(&self) -> &'static str {
+        Filter::name(self)
+    }
+
+    fn process(&self, bitext: Vec<BiText>, rejects: &mut Vec<Rejection>) -> io::Result<Vec<BiText>> {
+        let (kept, rejected) = self.filter_with_rejects(bitext);
+        rejects.extend(rejected);
+        Ok(kept)
+    }
+
+    fn modifies(&self) -> bool {
+        Filter::modifies(self)
+    }
+
+    fn can_score(&self) -> bool {
+        Filter::can_score(self)
+    }
+
+    fn score(&self, bitext: &BiText) -> Option<Score> {
+        Filter::score(self, bitext)
+    }
+
+    fn details(&self) -> BTreeMap<String, f64> {
+        Filter::details(self)
+    }
+}
+
+/// Runs a filter in score mode: instead of removing pairs, it writes its
+/// score to [`BiText::scores`] under its name and keeps all pairs. Scores
+/// per side are written as `<name>:source` and `<name>:target`.
+pub struct ScoringStep {
+    step: Box<dyn Step>,
+    score_name: String,
+}
+
+impl ScoringStep {
+    /// Returns the step back if it can not score pairs.
+    pub fn new(step: Box<dyn Step>) -> Result<Self, Box<dyn Step>> {
+        match step.can_score() {
+            true => Ok(ScoringStep { score_name: String::from(step.name()), step }),
+            false => Err(step),
+        }
+    }
+
+    /// Writes the scores under `score_name` instead of the name of the step,
+    /// so that several steps of the same kind can score the same pairs.
+    pub fn with_score_name(mut self, score_name: &str) -> Self {
+        self.score_name = String::from(score_name);
+        self
+    }
+}
+
+impl Step for ScoringStep {
+    fn name(&self) -> &'static str {
+        self.step.name()
+    }
+
+    fn process(&self, mut bitext: Vec<BiText>, _rejects: &mut Vec<Rejection>) -> io::Result<Vec<BiText>> {
+        bitext.par_iter_mut().for_each(|x| {
+            match self.step.score(x) {
+                Some(Score::Pair(score)) => {
+                    x.scores.insert(self.score_name.clone(), score);
+                }
+                Some(Score::Sides { source, target }) => {
+                    if let Some(source) = source {
+                        x.scores.insert(format!("{}:source", self.score_name), source);
+                    }
+                    if let Some(target) = target {
+                        x.scores.insert(format!("{}:target", self.score_name), target);
+                    }
+                }
+                None => {}
+            }
+        });
+        Ok(bitext)
+    }
+}
+
+impl Step for Cleaner {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn process(&self, bitext: Vec<BiText>, _rejects: &mut Vec<Rejection>) -> io::Result<Vec<BiText>> {
+        Ok((self.clean)(bitext))
+    }
+
+    fn modifies(&self) -> bool {
+        true
+    }
+}
+
+impl Step for ParallelDeduplicator {
+    fn name(&self) -> &'static str {
+        ParallelDeduplicator::name(self)
+    }
+
+    fn process(&self, bitext: Vec<BiText>, rejects: &mut Vec<Rejection>) -> io::Result<Vec<BiText>> {
+        let (kept, rejected) = self.deduplicate_chunk(bitext);
+        rejects.extend(rejected);
+        Ok(kept)
+    }
+
+    fn details(&self) -> BTreeMap<String, f64> {
+        BTreeMap::from([
+            (String::from("keys"), self.seen_keys() as f64),
+            (String::from("false_positive_rate"), self.false_positive_rate()),
+        ])
+    }
+
+    fn clusters(&self) -> Vec<DuplicateCluster> {
+        self.largest_clusters()
+    }
+
+    fn keeps_examples(&self) -> bool {
+        self.reports_clusters()
+    }
+}
+
+impl Step for ExternalDeduplicator {
+    fn name(&self) -> &'static str {
+        ExternalDeduplicator::name(self)
+    }
+
+    fn process(&self, bitext: Vec<BiText>, _rejects: &mut Vec<Rejection>) -> io::Result<Vec<BiText>> {
+        self.spill_chunk(bitext).map_err(|error| {
+            io::Error::new(error.kind(), format!("Could not write deduplication partitions: {}", error))
+        })?;
+        Ok(Vec::new())
+    }
+
+    fn finish(&self, rejects: &mut Vec<Rejection>) -> io::Result<Option<Vec<BiText>>> {
+        let batch = ExternalDeduplicator::finish(self).map_err(|error| {
+            io::Error::new(error.kind(), format!("Could not read deduplication partitions: {}", error))
+        })?;
+        let Some((kept, rejected)) = batch else {
+            return Ok(None);
+        };
+        rejects.extend(rejected);
+        Ok(Some(kept))
+    }
+
+    fn clusters(&self) -> Vec<DuplicateCluster> {
+        self.largest_clusters()
+    }
+
+    fn keeps_examples(&self) -> bool {
+        self.reports_clusters()
+    }
+}
+
+impl Step for NearDeduplicator {
+    fn name(&self) -> &'static str {
+        "near_deduplicator"
+    }
+
+    fn process(&self, bitext: Vec<BiText>, rejects: &mut Vec<Rejection>) -> io::Result<Vec<BiText>> {
+        let (kept, rejected) = self.deduplicate_chunk(bitext);
+        rejects.extend(rejected);
+        Ok(kept)
+    }
+
+    fn clusters(&self) -> Vec<DuplicateCluster> {
+        self.largest_clusters()
+    }
+
+    fn keeps_examples(&self) -> bool {
+        self.reports_clusters()
+    }
+}
+
+impl Step for LangIdAnnotator {
+    fn name(&self) -> &'static str {
+        "langid_annotator"
+    }
+
+    fn process(&self, bitext: Vec<BiText>, _rejects: &mut Vec<Rejection>) -> io::Result<Vec<BiText>> {
+        Ok(self.annotate_chunk(bitext))
+    }
+}
@@ -0,0 +1,470 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use crate::compression;
+use crate::model::BiText;
+
+/// Elements inside a `<seg>` that hold native codes instead of translatable text.
+const INLINE_CODE_ELEMENTS: [&[u8]; 5] = [b"bpt", b"ept", b"ph", b"it", b"ut"];
+
+#[derive(Debug)]
+pub enum TmxError {
+    Io(io::Error),
+    Xml { position: usize, source: quick_xml::Error },
+}
+
+impl fmt::Display for TmxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TmxError::Io(source) => write!(f, "Could not read TMX file: {}", source),
+            TmxError::Xml { position, source } => write!(f, "Invalid TMX at byte {}: {}", position, source),
+        }
+    }
+}
+
+impl std::error::Error for TmxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TmxError::Io(source) => Some(source),
+            TmxError::Xml { source, .. } => Some(source),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Variant {
+    lang: String,
+    text: String,
+    metadata: Vec<(String, String)>,
+}
+
+#[derive(Default)]
+struct TranslationUnit {
+    metadata: Vec<(String, String)>,
+    variants: Vec<Variant>,
+}
+
+/// Where the text of the current element ends up.
+enum Collect {
+    Nothing,
+    Segment,
+    Prop(String),
+    Note,
+}
+
+/// Lazily reads the `<tu>` elements of a TMX 1.4 file as [`BiText`]s.
+///
+/// The source variant is the one matching the requested source language, or
+/// the `srclang` of the header. Translation units where no source and target
+/// variant can be found are skipped and counted in [`TmxReader::skipped`].
+///
+/// Properties and notes are kept in [`BiText::metadata`]: `prop:<type>` and
+/// `note` for the translation unit, prefixed with `src:`/`trg:` for the variants.
+pub struct TmxReader<R: BufRead> {
+    reader: Reader<R>,
+    buffer: Vec<u8>,
+    src_lang: Option<String>,
+    trg_lang: Option<String>,
+    unit: Option<TranslationUnit>,
+    variant: Option<Variant>,
+    collect: Collect,
+    text: String,
+    code_depth: usize,
+    /// Number of translation units read so far.
+    units: u64,
+    skipped: usize,
+    done: bool,
+}
+
+impl TmxReader<Box<dyn BufRead>> {
+    pub fn open(file: &str) -> Result<Self, TmxError> {
+        let file = compression::open(file).map_err(TmxError::Io)?;
+        Ok(TmxReader::new(file))
+    }
+}
+
+impl<R: BufRead> TmxReader<R> {
+    pub fn new(reader: R) -> Self {
+        TmxReader {
+            reader: Reader::from_reader(reader),
+            buffer: Vec::new(),
+            src_lang: None,
+            trg_lang: None,
+            unit: None,
+            variant: None,
+            collect: Collect::Nothing,
+            text: String::new(),
+            code_depth: 0,
+            units: 0,
+            skipped: 0,
+            done: false,
+        }
+    }
+
+    /// Selects the variants to read, which is needed for multilingual TMX files.
+    pub fn with_languages(mut self, src_lang: Option<String>, trg_lang: Option<String>) -> Self {
+        self.src_lang = src_lang;
+        self.trg_lang = trg_lang;
+        self
+    }
+
+    /// Number of translation units that had no usable language pair.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    fn xml_error(&self, source: quick_xml::Error) -> TmxError {
+        TmxError::Xml { position: self.reader.buffer_position(), source }
+    }
+
+    fn attribute(&self, element: &BytesStart, name: &[u8]) -> Result<Option<String>, TmxError> {
+        for attribute in element.attributes() {
+            let attribute = attribute.map_err(|x| self.xml_error(x.into()))?;
+            if attribute.key.as_ref() == name {
+                let value = attribute.unescape_value().map_err(|x| self.xml_error(x))?;
+                return Ok(Some(value.to_string()));
+            }
+        }
+        Ok(None)
+    }
+
+    fn start(&mut self, element: &BytesStart, empty: bool) -> Result<(), TmxError> {
+        let name = element.local_name();
+        let name = name.as_ref();
+        if let Collect::Segment = self.collect {
+            if INLINE_CODE_ELEMENTS.contains(&name) && !empty {
+                self.code_depth += 1;
+            }
+            return Ok(());
+        }
+        match name {
+            b"header" => {
+                if self.src_lang.is_none() {
+                    self.src_lang = self.attribute(element, b"srclang")?.filter(|x| x != "*all*");
+                }
+            }
+            b"tu" => {
+                let mut unit = TranslationUnit::default();
+                if let Some(tuid) = self.attribute(element, b"tuid")? {
+                    unit.metadata.push((String::from("tuid"), tuid));
+                }
+                self.unit = Some(unit);
+            }
+            b"tuv" => {
+                let lang = match self.attribute(element, b"xml:lang")? {
+                    Some(lang) => lang,
+                    None => self.attribute(element, b"lang")?.unwrap_or_default(),
+                };
+                self.variant = Some(Variant { lang, ..Variant::default() });
+            }
+            b"seg" if !empty => self.collect = Collect::Segment,
+            b"prop" if !empty => {
+                let prop_type = self.attribute(element, b"type")?.unwrap_or_default();
+                self.collect = Collect::Prop(prop_type);
+            }
+            b"note" if !empty => self.collect = Collect::Note,
+            _ => {}
+        }
+        if empty {
+            self.end(name)?;
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, name: &[u8]) -> Result<Option<BiText>, TmxError> {
+        if let Collect::Segment = self.collect {
+            if name != b"seg" {
+                if INLINE_CODE_ELEMENTS.contains(&name) {
+                    self.code_depth = self.code_depth.saturating_sub(1);
+                }
+                return Ok(None);
+            }
+        }
+        let text = std::mem::take(&mut self.text);
+        match (name, std::mem::replace(&mut self.collect, Collect::Nothing)) {
+            (b"seg", Collect::Segment) => {
+                if let Some(variant) = self.variant.as_mut() {
+                    variant.text = text;
+                }
+            }
+            (b"prop", Collect::Prop(prop_type)) => self.add_metadata(format!("prop:{}", prop_type), text),
+            (b"note", Collect::Note) => self.add_metadata(String::from("note"), text),
+            (b"tuv", _) => {
+                if let (Some(unit), Some(variant)) = (self.unit.as_mut(), self.variant.take()) {
+                    unit.variants.push(variant);
+                }
+            }
+            (b"tu", _) => {
+                if let Some(unit) = self.unit.take() {
+                    self.units += 1;
+                    let mut bitext = self.to_bitext(unit);
+                    match bitext.as_mut() {
+                        Some(bitext) => bitext.line = Some(self.units),
+                        None => self.skipped += 1,
+                    }
+                    return Ok(bitext);
+                }
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn add_metadata(&mut self, key: String, value: String) {
+        let metadata = match (self.variant.as_mut(), self.unit.as_mut()) {
+            (Some(variant), _) => &mut variant.metadata,
+            (None, Some(unit)) => &mut unit.metadata,
+            (None, None) => return,
+        };
+        metadata.push((key, value));
+    }
+
+    fn to_bitext(&self, unit: TranslationUnit) -> Option<BiText> {
+        let mut variants = unit.variants;
+        let src_index = match &self.src_lang {
+            Some(lang) => find_variant(&variants, lang),
+            None if variants.len() == 2 => Some(0),
+            None => None,
+        }?;
+        let src = variants.remove(src_index);
+        let trg_index = match &self.trg_lang {
+            Some(lang) => find_variant(&variants, lang),
+            None if variants.len() == 1 => Some(0),
+            None => None,
+        }?;
+        let trg = variants.remove(trg_index);
+
+        let mut bitext = BiText::new(src.text, Some(src.lang), Some(trg.text), Some(trg.lang));
+        for (key, value) in unit.metadata {
+            add_entry(&mut bitext.metadata, key, value);
+        }
+        for (key, value) in src.metadata {
+            add_entry(&mut bitext.metadata, format!("src:{}", key), value);
+        }
+        for (key, value) in trg.metadata {
+            add_entry(&mut bitext.metadata, format!("trg:{}", key), value);
+        }
+        Some(bitext)
+    }
+
+    fn next_bitext(&mut self) -> Result<Option<BiText>, TmxError> {
+        loop {
+            let event = self.reader.read_event_into(&mut self.buffer);
+            let event = match event {
+                Ok(event) => event.into_owned(),
+                Err(error) => return Err(self.xml_error(error)),
+            };
+            self.buffer.clear();
+            match event {
+                Event::Start(element) => self.start(&element, false)?,
+                Event::Empty(element) => self.start(&element, true)?,
+                Event::End(element) => {
+                    if let Some(bitext) = self.end(element.local_name().as_ref())? {
+                        return Ok(Some(bitext));
+                    }
+                }
+                Event::Text(text) => {
+                    if !matches!(self.collect, Collect::Nothing) && self.code_depth == 0 {
+                        let text = text.unescape().map_err(|x| self.xml_error(x))?;
+                        self.text.push_str(&text);
+                    }
+                }
+                Event::CData(text) => {
+                    if !matches!(self.collect, Collect::Nothing) && self.code_depth == 0 {
+                        self.text.push_str(&String::from_utf8_lossy(&text.into_inner()));
+                    }
+                }
+                Event::Eof => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for TmxReader<R> {
+    type Item = Result<BiText, TmxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.next_bitext().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+/// Finds the variant in the expected language, preferring an exact match of
+/// the language code over one that only has the same primary subtag.
+fn find_variant(variants: &[Variant], expected: &str) -> Option<usize> {
+    variants
+        .iter()
+        .position(|x| x.lang.eq_ignore_ascii_case(expected))
+        .or_else(|| variants.iter().position(|x| same_language(&x.lang, expected)))
+}
+
+/// Compares the primary subtags of language codes case-insensitively, so that
+/// `en` matches `en-US` and `en-US` matches `en`.
+fn same_language(lang: &str, expected: &str) -> bool {
+    let primary = |x: &str| x.split(['-', '_']).next().unwrap_or_default().to_lowercase();
+    primary(lang) == primary(expected)
+}
+
+/// Repeated properties and notes are joined with a newline.
+fn add_entry(metadata: &mut BTreeMap<String, String>, key: String, value: String) {
+    metadata
+        .entry(key)
+        .and_modify(|x| {
+            x.push('\n');
+            x.push_str(&value)
+        })
+        .or_insert(value);
+}
+
+/// Writes [`BiText`]s as a TMX 1.4 document, restoring the properties and
+/// notes read by [`TmxReader`] from [`BiText::metadata`].
+pub struct TmxWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> TmxWriter<W> {
+    pub fn new(mut writer: W, src_lang: Option<&str>) -> io::Result<Self> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(writer, r#"<tmx version="1.4">"#)?;
+        writeln!(
+            writer,
+            r#"  <header creationtool="bitextcleaner" creationtoolversion="{}" segtype="sentence" o-tmf="bitextcleaner" adminlang="en" srclang="{}" datatype="plaintext"/>"#,
+            env!("CARGO_PKG_VERSION"),
+            escape(src_lang.unwrap_or("*all*"))
+        )?;
+        writeln!(writer, "  <body>")?;
+        Ok(TmxWriter { writer })
+    }
+
+    pub fn write(&mut self, bitext: &BiText) -> io::Result<()> {
+        match bitext.metadata.get("tuid") {
+            Some(tuid) => writeln!(self.writer, r#"    <tu tuid="{}">"#, escape(tuid))?,
+            None => writeln!(self.writer, "    <tu>")?,
+        }
+        self.write_metadata(bitext, "", "      ")?;
+        self.write_variant(bitext, "src:", bitext.language.as_deref(), &bitext.text)?;
+        self.write_variant(
+            bitext,
+            "trg:",
+            bitext.translation_language.as_deref(),
+            bitext.translation.as_deref().unwrap_or_default(),
+        )?;
+        writeln!(self.writer, "    </tu>")
+    }
+
+    fn write_variant(&mut self, bitext: &BiText, prefix: &str, lang: Option<&str>, text: &str) -> io::Result<()> {
+        writeln!(self.writer, r#"      <tuv xml:lang="{}">"#, escape(lang.unwrap_or("und")))?;
+        self.write_metadata(bitext, prefix, "        ")?;
+        writeln!(self.writer, "        <seg>{}</seg>", escape(text))?;
+        writeln!(self.writer, "      </tuv>")
+    }
+
+    /// TMX requires notes to come before properties.
+    fn write_metadata(&mut self, bitext: &BiText, prefix: &str, indent: &str) -> io::Result<()> {
+        if let Some(notes) = bitext.metadata.get(&format!("{}note", prefix)) {
+            for note in notes.split('\n') {
+                writeln!(self.writer, "{}<note>{}</note>", indent, escape(note))?;
+            }
+        }
+        let prop_prefix = format!("{}prop:", prefix);
+        for (key, values) in bitext.metadata.iter() {
+            if let Some(prop_type) = key.strip_prefix(&prop_prefix) {
+                for value in values.split('\n') {
+                    writeln!(self.writer, r#"{}<prop type="{}">{}</prop>"#, indent, escape(prop_type), escape(value))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        writeln!(self.writer, "  </body>")?;
+        writeln!(self.writer, "</tmx>")?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+pub fn write_tmx<W: Write>(writer: W, bitexts: &[BiText]) -> io::Result<W> {
+    let src_lang = bitexts.first().and_then(|x| x.language.as_deref());
+    let mut writer = TmxWriter::new(writer, src_lang)?;
+    for bitext in bitexts {
+        writer.write(bitext)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TMX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<tmx version="1.4">
+  <header creationtool="test" creationtoolversion="1" segtype="sentence" o-tmf="test" adminlang="en" srclang="en-US" datatype="plaintext"/>
+  <body>
+    <tu tuid="1">
+      <note>Checked</note>
+      <prop type="x-domain">legal</prop>
+      <tuv xml:lang="de-DE"><seg>Hallo <bpt i="1">&lt;b&gt;</bpt>Welt<ept i="1">&lt;/b&gt;</ept> &amp; Co</seg></tuv>
+      <tuv xml:lang="en-US"><prop type="x-origin">client</prop><seg>Hello <bpt i="1">&lt;b&gt;</bpt>world<ept i="1">&lt;/b&gt;</ept> &amp; co</seg></tuv>
+    </tu>
+    <tu>
+      <tuv xml:lang="en-US"><seg>Only one side</seg></tuv>
+    </tu>
+  </body>
+</tmx>"#;
+
+    #[test]
+    fn test_tmx_reader() {
+        let mut reader = TmxReader::new(TMX.as_bytes());
+        let bitexts: Vec<BiText> = reader.by_ref().collect::<Result<_, _>>().unwrap();
+        assert_eq!(bitexts.len(), 1);
+        assert_eq!(reader.skipped(), 1);
+        let bitext = &bitexts[0];
+        assert_eq!(bitext.text, "Hello world & co");
+        assert_eq!(bitext.translation, Some(String::from("Hallo Welt & Co")));
+        assert_eq!(bitext.language, Some(String::from("en-US")));
+        assert_eq!(bitext.translation_language, Some(String::from("de-DE")));
+        assert_eq!(bitext.metadata.get("tuid"), Some(&String::from("1")));
+        assert_eq!(bitext.metadata.get("note"), Some(&String::from("Checked")));
+        assert_eq!(bitext.metadata.get("prop:x-domain"), Some(&String::from("legal")));
+        assert_eq!(bitext.metadata.get("src:prop:x-origin"), Some(&String::from("client")));
+    }
+
+    #[test]
+    fn test_tmx_roundtrip() {
+        let bitexts: Vec<BiText> = TmxReader::new(TMX.as_bytes()).collect::<Result<_, _>>().unwrap();
+        let written = write_tmx(Vec::new(), &bitexts).unwrap();
+        let reread: Vec<BiText> = TmxReader::new(written.as_slice()).collect::<Result<_, _>>().unwrap();
+        assert_eq!(bitexts, reread);
+    }
+
+    #[test]
+    fn test_tmx_reader_languages() {
+        assert!(same_language("en", "en-US"));
+        assert!(same_language("en-US", "en"));
+        assert!(!same_language("en-US", "de"));
+
+        let reader = TmxReader::new(TMX.as_bytes()).with_languages(Some(String::from("de")), Some(String::from("en-GB")));
+        let bitexts: Vec<BiText> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(bitexts[0].language, Some(String::from("de-DE")));
+        assert_eq!(bitexts[0].translation_language, Some(String::from("en-US")));
+    }
+
+    #[test]
+    fn test_tmx_reader_invalid() {
+        let result: Result<Vec<BiText>, TmxError> = TmxReader::new("<tmx><body><tu></body>".as_bytes()).collect();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,345 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use csv::{QuoteStyle, ReaderBuilder, StringRecord, WriterBuilder};
+use crate::compression;
+use crate::model::BiText;
+
+/// Layout of a delimiter separated bitext file.
+///
+/// Columns other than the source and target are kept in [`BiText::metadata`]
+/// as `column:<index>` and written back to the same position, for up to
+/// [`MAX_COLUMNS`] columns.
+#[derive(Debug, Clone)]
+pub struct TsvFormat {
+    pub delimiter: u8,
+    pub src_column: usize,
+    pub trg_column: usize,
+    /// Whether fields may be quoted. Without quoting, delimiters and newlines
+    /// inside a segment are replaced by a space when writing.
+    pub quoting: bool,
+}
+
+impl Default for TsvFormat {
+    /// The Bicleaner/Paracrawl convention: tab separated, source and target
+    /// in the first two columns, no quoting.
+    fn default() -> Self {
+        TsvFormat {
+            delimiter: b'\t',
+            src_column: 0,
+            trg_column: 1,
+            quoting: false,
+        }
+    }
+}
+
+impl TsvFormat {
+    /// Comma separated with RFC 4180 quoting, which allows for newlines in segments.
+    pub fn csv() -> Self {
+        TsvFormat {
+            delimiter: b',',
+            quoting: true,
+            ..TsvFormat::default()
+        }
+    }
+
+    /// Fails if source and target are in the same column.
+    pub fn with_columns(mut self, src_column: usize, trg_column: usize) -> Result<Self, TsvError> {
+        if src_column == trg_column {
+            return Err(TsvError::SameColumn { column: src_column });
+        }
+        self.src_column = src_column;
+        self.trg_column = trg_column;
+        Ok(self)
+    }
+}
+
+/// Rows with more columns are not written, so that a bad `column:<index>`
+/// key can not make the writer fill a row with millions of empty fields.
+pub const MAX_COLUMNS: usize = 1024;
+
+#[derive(Debug)]
+pub enum TsvError {
+    Csv(csv::Error),
+    MissingColumn { line: u64, column: usize, columns: usize },
+    SameColumn { column: usize },
+    TooManyColumns { column: usize },
+}
+
+impl fmt::Display for TsvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TsvError::Csv(source) => write!(f, "{}", source),
+            TsvError::MissingColumn { line, column, columns } => write!(
+                f,
+                "Line {} has {} columns, but column {} was expected",
+                line, columns, column
+            ),
+            TsvError::SameColumn { column } => write!(f, "Source and target can not both be in column {}", column),
+            TsvError::TooManyColumns { column } => {
+                write!(f, "Column {} is out of range, at most {} columns are written", column, MAX_COLUMNS)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TsvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TsvError::Csv(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<csv::Error> for TsvError {
+    fn from(error: csv::Error) -> Self {
+        TsvError::Csv(error)
+    }
+}
+
+/// Lazily reads a delimiter separated bitext file.
+///
+/// Blank lines hold no columns and are skipped, but they are counted in the
+/// line numbers of the pairs.
+pub struct TsvReader<R: Read> {
+    reader: csv::Reader<LineCounter<R>>,
+    record: StringRecord,
+    format: TsvFormat,
+    src_lang: Option<String>,
+    trg_lang: Option<String>,
+}
+
+impl TsvReader<Box<dyn BufRead>> {
+    pub fn open(file: &str, format: TsvFormat, src_lang: Option<String>, trg_lang: Option<String>) -> Result<Self, TsvError> {
+        let file = compression::open(file).map_err(csv::Error::from)?;
+        Ok(TsvReader::new(file, format, src_lang, trg_lang))
+    }
+}
+
+impl<R: Read> TsvReader<R> {
+    pub fn new(reader: R, format: TsvFormat, src_lang: Option<String>, trg_lang: Option<String>) -> Self {
+        let reader = ReaderBuilder::new()
+            .delimiter(format.delimiter)
+            .has_headers(false)
+            .flexible(true)
+            .quoting(format.quoting)
+            .from_reader(LineCounter { inner: reader, offset: 0, newlines: VecDeque::new(), lines: 0 });
+        TsvReader {
+            reader,
+            record: StringRecord::new(),
+            format,
+            src_lang,
+            trg_lang,
+        }
+    }
+}
+
+/// Counts the newlines of the input, as the positions of the csv reader do
+/// not count the blank lines it skips.
+struct LineCounter<R> {
+    inner: R,
+    /// Bytes read so far.
+    offset: u64,
+    /// Offsets of the newlines read ahead of the last record.
+    newlines: VecDeque<u64>,
+    /// Newlines up to the end of the last record.
+    lines: u64,
+}
+
+impl<R> LineCounter<R> {
+    /// The line of the last byte before `end`, for increasing `end`.
+    fn last_line(&mut self, end: u64) -> u64 {
+        let mut terminated = false;
+        while let Some(&newline) = self.newlines.front().filter(|x| **x < end) {
+            terminated = newline + 1 == end;
+            self.lines += 1;
+            self.newlines.pop_front();
+        }
+        match terminated {
+            true => self.lines,
+            false => self.lines + 1,
+        }
+    }
+}
+
+impl<R: Read> Read for LineCounter<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let newlines = buf[..read].iter().enumerate().filter(|(_, x)| **x == b'\n');
+        self.newlines.extend(newlines.map(|(index, _)| self.offset + index as u64));
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read> Iterator for TsvReader<R> {
+    type Item = Result<BiText, TsvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(error) => return Some(Err(error.into())),
+        }
+        let record = &self.record;
+        let end = self.reader.position().byte();
+        let last_line = self.reader.get_mut().last_line(end);
+        let line = last_line - record.iter().map(|x| x.matches('\n').count() as u64).sum::<u64>();
+        let column = |index: usize| {
+            record.get(index).map(String::from).ok_or(TsvError::MissingColumn {
+                line,
+                column: index,
+                columns: record.len(),
+            })
+        };
+        let (text, translation) = match (column(self.format.src_column), column(self.format.trg_column)) {
+            (Ok(text), Ok(translation)) => (text, translation),
+            (Err(error), _) | (_, Err(error)) => return Some(Err(error)),
+        };
+        let mut bitext = BiText::new(text, self.src_lang.clone(), Some(translation), self.trg_lang.clone());
+        bitext.line = Some(line);
+        for (index, value) in record.iter().enumerate() {
+            if index != self.format.src_column && index != self.format.trg_column {
+                bitext.metadata.insert(format!("column:{}", index), String::from(value));
+            }
+        }
+        Some(Ok(bitext))
+    }
+}
+
+/// Writes bitexts in the same layout [`TsvReader`] reads them.
+pub struct TsvWriter<W: Write> {
+    writer: csv::Writer<W>,
+    format: TsvFormat,
+}
+
+impl<W: Write> TsvWriter<W> {
+    pub fn new(writer: W, format: TsvFormat) -> Self {
+        let quote_style = if format.quoting { QuoteStyle::Necessary } else { QuoteStyle::Never };
+        let writer = WriterBuilder::new()
+            .delimiter(format.delimiter)
+            .has_headers(false)
+            .flexible(true)
+            .quote_style(quote_style)
+            .from_writer(writer);
+        TsvWriter { writer, format }
+    }
+
+    fn field(&self, value: &str) -> String {
+        if self.format.quoting {
+            return String::from(value);
+        }
+        let delimiter = self.format.delimiter as char;
+        value.replace(['\n', '\r', delimiter], " ")
+    }
+
+    pub fn write(&mut self, bitext: &BiText) -> Result<(), TsvError> {
+        let extra_columns: Vec<(usize, &String)> = bitext
+            .metadata
+            .iter()
+            .filter_map(|(key, value)| Some((key.strip_prefix("column:")?.parse().ok()?, value)))
+            .collect();
+        let width = extra_columns
+            .iter()
+            .map(|(index, _)| index + 1)
+            .chain([self.format.src_column + 1, self.format.trg_column + 1])
+            .max()
+            .unwrap_or_default();
+        if width > MAX_COLUMNS {
+            return Err(TsvError::TooManyColumns { column: width - 1 });
+        }
+        let mut row = vec![String::new(); width];
+        for (index, value) in extra_columns {
+            row[index] = self.field(value);
+        }
+        row[self.format.src_column] = self.field(&bitext.text);
+        row[self.format.trg_column] = self.field(bitext.translation.as_deref().unwrap_or_default());
+        self.writer.write_record(&row)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, TsvError> {
+        self.writer.flush().map_err(csv::Error::from)?;
+        self.writer.into_inner().map_err(|x| TsvError::Csv(x.into_error().into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tsv_reader() {
+        let input = "http://a.com\thttp://b.com\tHello \"world\"\tHallo Welt\t0.9\n";
+        let format = TsvFormat::default().with_columns(2, 3).unwrap();
+        let bitexts: Vec<BiText> = TsvReader::new(input.as_bytes(), format, None, None)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(bitexts.len(), 1);
+        assert_eq!(bitexts[0].text, "Hello \"world\"");
+        assert_eq!(bitexts[0].translation, Some(String::from("Hallo Welt")));
+        assert_eq!(bitexts[0].metadata.get("column:0"), Some(&String::from("http://a.com")));
+        assert_eq!(bitexts[0].metadata.get("column:4"), Some(&String::from("0.9")));
+    }
+
+    #[test]
+    fn test_tsv_reader_missing_column() {
+        let input = "Hello\tHallo\nOnly source\n";
+        let results: Vec<Result<BiText, TsvError>> = TsvReader::new(input.as_bytes(), TsvFormat::default(), None, None).collect();
+        assert!(results[0].is_ok());
+        match &results[1] {
+            Err(TsvError::MissingColumn { line, column, .. }) => assert_eq!((*line, *column), (2, 1)),
+            _ => panic!("Expected a missing column"),
+        }
+        assert!(matches!(TsvFormat::default().with_columns(1, 1), Err(TsvError::SameColumn { column: 1 })));
+    }
+
+    #[test]
+    fn test_tsv_blank_lines() {
+        let input = "a\tb\n\n\r\nc\td";
+        let bitexts: Vec<BiText> = TsvReader::new(input.as_bytes(), TsvFormat::default(), None, None)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(bitexts.iter().map(|x| (x.text.as_str(), x.line)).collect::<Vec<_>>(), vec![("a", Some(1)), ("c", Some(4))]);
+        let input = "\"x\ny\",b\n\n\nc,d\n";
+        let bitexts: Vec<BiText> = TsvReader::new(input.as_bytes(), TsvFormat::csv(), None, None)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(bitexts.iter().map(|x| x.line).collect::<Vec<_>>(), vec![Some(1), Some(5)]);
+    }
+
+    #[test]
+    fn test_tsv_roundtrip() {
+        let input = "1\tHello\tHallo\n2\tWorld\tWelt\n";
+        let format = TsvFormat::default().with_columns(1, 2).unwrap();
+        let bitexts: Vec<BiText> = TsvReader::new(input.as_bytes(), format.clone(), None, None)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let mut writer = TsvWriter::new(Vec::new(), format);
+        bitexts.iter().for_each(|x| writer.write(x).unwrap());
+        assert_eq!(String::from_utf8(writer.finish().unwrap()).unwrap(), input);
+
+        let mut bitext = BiText::new(String::from("Hello"), None, Some(String::from("Hallo")), None);
+        bitext.metadata.insert(String::from("column:4000000000"), String::from("x"));
+        let mut writer = TsvWriter::new(Vec::new(), TsvFormat::default());
+        assert!(matches!(writer.write(&bitext), Err(TsvError::TooManyColumns { column: 4000000000 })));
+    }
+
+    #[test]
+    fn test_tsv_embedded_newlines() {
+        let bitext = BiText::new(String::from("Hello\tthere\nworld"), None, Some(String::from("Hallo, Welt")), None);
+        let mut writer = TsvWriter::new(Vec::new(), TsvFormat::default());
+        writer.write(&bitext).unwrap();
+        assert_eq!(String::from_utf8(writer.finish().unwrap()).unwrap(), "Hello there world\tHallo, Welt\n");
+
+        let mut writer = TsvWriter::new(Vec::new(), TsvFormat::csv());
+        writer.write(&bitext).unwrap();
+        let written = writer.finish().unwrap();
+        let reread: Vec<BiText> = TsvReader::new(written.as_slice(), TsvFormat::csv(), None, None)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(reread, vec![BiText { line: Some(1), ..bitext }]);
+    }
+}